@@ -0,0 +1,69 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ipnet::{IpNet, Ipv4Net};
+use rusk_socks5::ip_filter::{Rule, RuleSet};
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// xorshift64, so the rule sets are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn ipv4(&mut self) -> Ipv4Addr {
+        Ipv4Addr::from(self.next() as u32)
+    }
+}
+
+/// Blocklist-like rules: mostly single hosts with some /16-/24 networks.
+fn rules(count: usize, rng: &mut Rng) -> Vec<Rule> {
+    (0..count)
+        .map(|_| {
+            let prefix_len = match rng.next() % 10 {
+                0 => 16 + (rng.next() % 9) as u8,
+                _ => 32,
+            };
+            let net = Ipv4Net::new(rng.ipv4(), prefix_len).unwrap().trunc();
+            Rule::Cidr(IpNet::V4(net))
+        })
+        .collect()
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ip_rule_lookup");
+    // The linear scan over 100k rules takes over a second per iteration
+    group.sample_size(10);
+    for count in [100, 10_000, 100_000] {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let rules = rules(count, &mut rng);
+        let mut set = RuleSet::new();
+        for rule in &rules {
+            set.insert(rule.clone());
+        }
+        let probes: Vec<IpAddr> = (0..1024).map(|_| IpAddr::V4(rng.ipv4())).collect();
+        for ip in &probes {
+            assert_eq!(set.contains(ip), rules.iter().any(|r| r.matches(ip)), "{}", ip);
+        }
+
+        group.bench_with_input(BenchmarkId::new("vec_scan", count), &probes, |b, probes| {
+            b.iter(|| {
+                probes
+                    .iter()
+                    .filter(|ip| rules.iter().any(|r| r.matches(black_box(ip))))
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("prefix_trie", count), &probes, |b, probes| {
+            b.iter(|| probes.iter().filter(|ip| set.contains(black_box(ip))).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+use crate::errors::{Result, ServerError};
+use crate::users::UserStore;
+use async_trait::async_trait;
+use moka::future::Cache;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Who a client was authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// `None` for anonymous clients.
+    pub username: Option<String>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Identity { username: None }
+    }
+
+    pub fn user(username: &str) -> Self {
+        Identity {
+            username: Some(username.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.username {
+            Some(username) => write!(f, "{}", username),
+            None => write!(f, "<anonymous>"),
+        }
+    }
+}
+
+/// Decides which clients may use the proxy.
+///
+/// Used for SOCKS5 method negotiation and RFC 1929 credentials, SOCKS4
+/// requests (which can only be anonymous) and HTTP `Proxy-Authorization`.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Whether username/password credentials are checked at all. When false,
+    /// the username/password method is never selected.
+    fn accepts_credentials(&self) -> bool {
+        true
+    }
+
+    /// Whether clients that send no credentials are let in.
+    fn allows_anonymous(&self) -> bool {
+        false
+    }
+
+    /// Checks a username/password pair sent by `client`. Rejections are
+    /// reported as [`ServerError::AuthenticationFailed`].
+    async fn authenticate(&self, client: SocketAddr, username: &str, password: &str) -> Result<Identity>;
+}
+
+/// Checks credentials against a fixed [`UserStore`].
+pub struct StaticAuthenticator {
+    users: UserStore,
+}
+
+impl StaticAuthenticator {
+    pub fn new(users: UserStore) -> Self {
+        StaticAuthenticator { users }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    fn accepts_credentials(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    async fn authenticate(&self, _client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        if self.users.verify(username, password).await {
+            Ok(Identity::user(username))
+        } else {
+            Err(ServerError::AuthenticationFailed(
+                "Invalid username or password".to_string(),
+            ))
+        }
+    }
+}
+
+/// Lets clients in without credentials. Clients that do send credentials are
+/// checked by the wrapped authenticator, if any.
+#[derive(Default)]
+pub struct AnonymousAuthenticator {
+    credentials: Option<Arc<dyn Authenticator>>,
+}
+
+impl AnonymousAuthenticator {
+    pub fn new() -> Self {
+        AnonymousAuthenticator { credentials: None }
+    }
+
+    pub fn with_credentials(credentials: Arc<dyn Authenticator>) -> Self {
+        AnonymousAuthenticator {
+            credentials: Some(credentials),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for AnonymousAuthenticator {
+    fn accepts_credentials(&self) -> bool {
+        self.credentials
+            .as_ref()
+            .is_some_and(|c| c.accepts_credentials())
+    }
+
+    fn allows_anonymous(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(&self, client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        match &self.credentials {
+            Some(credentials) => credentials.authenticate(client, username, password).await,
+            None => Err(ServerError::AuthenticationFailed(
+                "Credentials are not accepted".to_string(),
+            )),
+        }
+    }
+}
+
+/// Remembers recent outcomes of an external authentication backend, with
+/// separate lifetimes for accepted and rejected logins. Keys are hashes of the
+/// credentials so plaintext passwords are not kept in memory.
+pub struct AuthCache {
+    allowed: Cache<[u8; 32], Identity>,
+    denied: Cache<[u8; 32], ()>,
+}
+
+impl AuthCache {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Self {
+        let allowed = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(ttl)
+            .build();
+        let denied = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(negative_ttl)
+            .build();
+        AuthCache { allowed, denied }
+    }
+
+    /// Hashes everything the backend's decision depends on into a cache key.
+    pub fn key(parts: &[&str]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Returns the cached outcome for `key`, if any.
+    pub async fn get(&self, key: &[u8; 32]) -> Option<Result<Identity>> {
+        if let Some(identity) = self.allowed.get(key).await {
+            return Some(Ok(identity));
+        }
+        if self.denied.contains_key(key) {
+            return Some(Err(ServerError::AuthenticationFailed(
+                "Invalid username or password".to_string(),
+            )));
+        }
+        None
+    }
+
+    pub async fn allow(&self, key: [u8; 32], identity: Identity) {
+        self.allowed.insert(key, identity).await;
+    }
+
+    pub async fn deny(&self, key: [u8; 32]) {
+        self.denied.insert(key, ()).await;
+    }
+}
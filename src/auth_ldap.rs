@@ -0,0 +1,193 @@
+use crate::auth::{AuthCache, Authenticator, Identity};
+use crate::errors::{Result, ServerError};
+use async_trait::async_trait;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+
+/// LDAP result code for a failed bind with a wrong DN or password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Settings for [`LdapAuthenticator`].
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` URL of the directory server.
+    pub url: String,
+    /// Service account used for searches. Searches are anonymous when unset.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Subtree searched for user entries.
+    pub base_dn: String,
+    /// Search filter for the user entry; `{username}` is replaced with the
+    /// escaped login name.
+    pub user_filter: String,
+    /// DN of a group the user must belong to (groupOfNames, groupOfUniqueNames
+    /// or posixGroup).
+    pub required_group: Option<String>,
+    /// Maximum number of pooled connections.
+    pub pool_size: usize,
+    /// Deadline for a whole login check.
+    pub timeout: Duration,
+    pub cache_ttl: Duration,
+    pub negative_cache_ttl: Duration,
+}
+
+/// Outcome of a directory lookup that reached the server.
+enum Verdict {
+    Allow,
+    Deny(&'static str),
+}
+
+/// Authenticates credentials with an LDAP search-then-bind: the user entry is
+/// found with the service account, then its DN is bound with the supplied
+/// password. Connections are pooled and rebound to the service account after
+/// every check.
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+    idle: Mutex<Vec<Ldap>>,
+    permits: Semaphore,
+    cache: AuthCache,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> Self {
+        let permits = Semaphore::new(config.pool_size.max(1));
+        let cache = AuthCache::new(config.cache_ttl, config.negative_cache_ttl);
+        LdapAuthenticator {
+            config,
+            idle: Mutex::new(Vec::new()),
+            permits,
+            cache,
+        }
+    }
+
+    async fn connect(&self) -> std::result::Result<Ldap, ldap3::LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.config.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        self.bind_service(&mut ldap).await?;
+        Ok(ldap)
+    }
+
+    /// Binds `ldap` as the service account, or anonymously if none is configured.
+    async fn bind_service(&self, ldap: &mut Ldap) -> std::result::Result<(), ldap3::LdapError> {
+        let bind_dn = self.config.bind_dn.as_deref().unwrap_or_default();
+        let bind_password = self.config.bind_password.as_deref().unwrap_or_default();
+        ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+        Ok(())
+    }
+
+    async fn checkout(&self) -> std::result::Result<Ldap, ldap3::LdapError> {
+        while let Some(mut ldap) = self.idle.lock().await.pop() {
+            if !ldap.is_closed() {
+                return Ok(ldap);
+            }
+        }
+        self.connect().await
+    }
+
+    async fn check(&self, ldap: &mut Ldap, username: &str, password: &str) -> std::result::Result<Verdict, ldap3::LdapError> {
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(&self.config.base_dn, Scope::Subtree, &filter, vec!["1.1"])
+            .await?
+            .success()?;
+        let user_dn = match entries.len() {
+            0 => return Ok(Verdict::Deny("no such user")),
+            1 => SearchEntry::construct(entries.into_iter().next().unwrap()).dn,
+            _ => return Ok(Verdict::Deny("ambiguous user filter")),
+        };
+
+        let bind = ldap.simple_bind(&user_dn, password).await?;
+        if bind.rc == INVALID_CREDENTIALS {
+            self.bind_service(ldap).await?;
+            return Ok(Verdict::Deny("invalid password"));
+        }
+        bind.success()?;
+
+        // Back to the service identity for the group lookup and the next user
+        self.bind_service(ldap).await?;
+
+        if let Some(group) = &self.config.required_group {
+            let filter = format!(
+                "(|(member={dn})(uniqueMember={dn})(memberUid={uid}))",
+                dn = ldap_escape(user_dn.as_str()),
+                uid = ldap_escape(username)
+            );
+            let (entries, _) = ldap
+                .search(group, Scope::Base, &filter, vec!["1.1"])
+                .await?
+                .success()?;
+            if entries.is_empty() {
+                return Ok(Verdict::Deny("not a member of the required group"));
+            }
+        }
+
+        Ok(Verdict::Allow)
+    }
+
+    async fn query(&self, username: &str, password: &str) -> std::result::Result<Verdict, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+        let mut ldap = self.checkout().await.map_err(|e| e.to_string())?;
+        match self.check(&mut ldap, username, password).await {
+            Ok(verdict) => {
+                self.idle.lock().await.push(ldap);
+                Ok(verdict)
+            }
+            Err(e) => {
+                // Don't put a connection in an unknown state back in the pool
+                let _ = ldap.unbind().await;
+                Err(e.to_string())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(&self, _client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        // An empty password would be an unauthenticated bind, which succeeds
+        if username.is_empty() || password.is_empty() {
+            return Err(ServerError::AuthenticationFailed(
+                "Empty username or password".to_string(),
+            ));
+        }
+
+        let key = AuthCache::key(&[username, password]);
+        if let Some(outcome) = self.cache.get(&key).await {
+            log::debug!("LDAP auth cache hit: user={}, allowed={}", username, outcome.is_ok());
+            return outcome;
+        }
+
+        let verdict = match tokio::time::timeout(self.config.timeout, self.query(username, password)).await {
+            Ok(verdict) => verdict,
+            Err(_) => Err(format!("timed out after {:?}", self.config.timeout)),
+        };
+
+        match verdict {
+            Ok(Verdict::Allow) => {
+                let identity = Identity::user(username);
+                self.cache.allow(key, identity.clone()).await;
+                Ok(identity)
+            }
+            Ok(Verdict::Deny(reason)) => {
+                log::debug!("LDAP rejected {}: {}", username, reason);
+                self.cache.deny(key).await;
+                Err(ServerError::AuthenticationFailed(
+                    "Invalid username or password".to_string(),
+                ))
+            }
+            Err(e) => {
+                log::warn!("LDAP authentication for {} failed: {}", username, e);
+                Err(ServerError::AuthBackendUnavailable(format!(
+                    "Directory unavailable: {}",
+                    e
+                )))
+            }
+        }
+    }
+}
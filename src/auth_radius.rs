@@ -0,0 +1,289 @@
+use crate::auth::{Authenticator, Identity};
+use crate::errors::{Result, ServerError};
+use async_trait::async_trait;
+use hmac::{Hmac, KeyInit, Mac};
+use md5::{Digest, Md5};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCESS_CHALLENGE: u8 = 11;
+
+const ATTR_USER_NAME: u8 = 1;
+const ATTR_USER_PASSWORD: u8 = 2;
+const ATTR_CALLING_STATION_ID: u8 = 31;
+const ATTR_NAS_IDENTIFIER: u8 = 32;
+const ATTR_MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const DEFAULT_PORT: u16 = 1812;
+const HEADER_LEN: usize = 20;
+const MAX_PACKET_LEN: usize = 4096;
+const MAX_ATTRIBUTE_LEN: usize = 253;
+/// User-Password is at most 128 bytes (RFC 2865 section 5.2).
+const MAX_PASSWORD_LEN: usize = 128;
+
+/// Settings for [`RadiusAuthenticator`].
+#[derive(Debug, Clone)]
+pub struct RadiusConfig {
+    /// `host[:port]` of each server, tried in order on failure. The port
+    /// defaults to 1812.
+    pub servers: Vec<String>,
+    /// Shared secret between the proxy and the servers.
+    pub secret: String,
+    /// Time to wait for an answer to a single Access-Request.
+    pub timeout: Duration,
+    /// Retransmissions to a server before failing over to the next one.
+    pub retries: u32,
+    /// Sent as NAS-Identifier.
+    pub nas_identifier: String,
+}
+
+/// Authenticates credentials with a RADIUS Access-Request using PAP (RFC 2865).
+///
+/// Servers are tried in order, starting with the last one that answered; a
+/// server that does not answer within the timeout after all retries is
+/// skipped. Access-Challenge is treated as a rejection because SOCKS and HTTP
+/// proxy clients cannot answer a challenge.
+///
+/// Responses must carry a valid Message-Authenticator (RFC 3579). The MD5
+/// Response Authenticator alone can be forged by an attacker on the path
+/// (BlastRADIUS, CVE-2024-3596), so unsigned responses are ignored.
+pub struct RadiusAuthenticator {
+    config: RadiusConfig,
+    preferred: AtomicUsize,
+}
+
+impl RadiusAuthenticator {
+    pub fn new(mut config: RadiusConfig) -> Result<Self> {
+        if config.servers.is_empty() {
+            return Err(ServerError::Unknown("No RADIUS server configured".to_string()));
+        }
+        if config.secret.is_empty() {
+            return Err(ServerError::Unknown("The RADIUS shared secret is empty".to_string()));
+        }
+        for server in &mut config.servers {
+            // Bare hostnames and IPv4 addresses; IPv6 needs `[addr]:port`
+            if !server.contains(':') {
+                *server = format!("{}:{}", server, DEFAULT_PORT);
+            }
+        }
+        Ok(RadiusAuthenticator {
+            config,
+            preferred: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends the request to one server, retransmitting on timeout.
+    /// Returns the response code.
+    async fn exchange(&self, server: &str, packet: &[u8], authenticator: &[u8; 16]) -> std::result::Result<u8, String> {
+        let address = tokio::net::lookup_host(server)
+            .await
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("no address for {}", server))?;
+        let bind_address = match address {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(bind_address).await.map_err(|e| e.to_string())?;
+        socket.connect(address).await.map_err(|e| e.to_string())?;
+
+        let mut buf = vec![0u8; MAX_PACKET_LEN];
+        for attempt in 0..=self.config.retries {
+            socket.send(packet).await.map_err(|e| e.to_string())?;
+            let deadline = tokio::time::Instant::now() + self.config.timeout;
+            loop {
+                let n = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                    Ok(res) => res.map_err(|e| e.to_string())?,
+                    Err(_) => break,
+                };
+                // Ignore anything that is not a genuine answer to this request
+                match self.verify_response(&buf[..n], packet[1], authenticator) {
+                    Some(code) => return Ok(code),
+                    None => log::debug!("Ignoring invalid RADIUS response from {}", address),
+                }
+            }
+            log::debug!(
+                "RADIUS server {} did not answer (attempt {}/{})",
+                address,
+                attempt + 1,
+                self.config.retries + 1
+            );
+        }
+        Err(format!("{} timed out", server))
+    }
+
+    /// Checks the identifier, length, Response Authenticator and
+    /// Message-Authenticator of a reply and returns its code.
+    fn verify_response(&self, response: &[u8], identifier: u8, request_authenticator: &[u8; 16]) -> Option<u8> {
+        if response.len() < HEADER_LEN || response[1] != identifier {
+            return None;
+        }
+        let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+        if length < HEADER_LEN || length > response.len() {
+            return None;
+        }
+        let response = &response[..length];
+
+        let mut hasher = Md5::new();
+        hasher.update(&response[..4]);
+        hasher.update(request_authenticator);
+        hasher.update(&response[HEADER_LEN..]);
+        hasher.update(self.config.secret.as_bytes());
+        let expected = hasher.finalize();
+        if expected.as_slice() != &response[4..HEADER_LEN] {
+            return None;
+        }
+        if !self.verify_message_authenticator(response, request_authenticator) {
+            return None;
+        }
+
+        Some(response[0])
+    }
+
+    /// Checks that a reply holds exactly one Message-Authenticator and that it
+    /// is the HMAC-MD5 of the reply with the Request Authenticator in the
+    /// header and the attribute zeroed (RFC 3579 section 3.2).
+    fn verify_message_authenticator(&self, response: &[u8], request_authenticator: &[u8; 16]) -> bool {
+        let mut found = None;
+        let mut position = HEADER_LEN;
+        while position < response.len() {
+            let Some(&len) = response.get(position + 1) else {
+                return false;
+            };
+            let len = len as usize;
+            if len < 2 || position + len > response.len() {
+                return false;
+            }
+            if response[position] == ATTR_MESSAGE_AUTHENTICATOR {
+                if len != 18 || found.is_some() {
+                    return false;
+                }
+                found = Some(position + 2);
+            }
+            position += len;
+        }
+        let Some(offset) = found else {
+            return false;
+        };
+
+        let mut signed = response.to_vec();
+        signed[4..HEADER_LEN].copy_from_slice(request_authenticator);
+        signed[offset..offset + 16].fill(0);
+        let mut mac = self.message_authenticator();
+        mac.update(&signed);
+        mac.verify_slice(&response[offset..offset + 16]).is_ok()
+    }
+
+    fn message_authenticator(&self) -> Hmac<Md5> {
+        <Hmac<Md5> as KeyInit>::new_from_slice(self.config.secret.as_bytes())
+            .expect("HMAC accepts keys of any length")
+    }
+
+    fn build_request(&self, client: SocketAddr, username: &str, password: &str) -> (Vec<u8>, [u8; 16]) {
+        let identifier: u8 = rand::random();
+        let authenticator: [u8; 16] = rand::random();
+
+        let mut packet = vec![ACCESS_REQUEST, identifier, 0, 0];
+        packet.extend_from_slice(&authenticator);
+        push_attribute(&mut packet, ATTR_USER_NAME, username.as_bytes());
+        push_attribute(
+            &mut packet,
+            ATTR_USER_PASSWORD,
+            &hide_password(password.as_bytes(), self.config.secret.as_bytes(), &authenticator),
+        );
+        push_attribute(
+            &mut packet,
+            ATTR_NAS_IDENTIFIER,
+            self.config.nas_identifier.as_bytes(),
+        );
+        push_attribute(
+            &mut packet,
+            ATTR_CALLING_STATION_ID,
+            client.ip().to_canonical().to_string().as_bytes(),
+        );
+
+        // Message-Authenticator (RFC 3579) is computed with itself zeroed
+        let offset = packet.len() + 2;
+        push_attribute(&mut packet, ATTR_MESSAGE_AUTHENTICATOR, &[0u8; 16]);
+        let length = packet.len() as u16;
+        packet[2..4].copy_from_slice(&length.to_be_bytes());
+        let mut mac = self.message_authenticator();
+        mac.update(&packet);
+        packet[offset..offset + 16].copy_from_slice(&mac.finalize().into_bytes());
+
+        (packet, authenticator)
+    }
+}
+
+fn push_attribute(packet: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    let value = &value[..value.len().min(MAX_ATTRIBUTE_LEN)];
+    packet.push(kind);
+    packet.push(value.len() as u8 + 2);
+    packet.extend_from_slice(value);
+}
+
+/// Hides a User-Password as described in RFC 2865 section 5.2. The password
+/// must not be longer than [`MAX_PASSWORD_LEN`].
+fn hide_password(password: &[u8], secret: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+    let padded_len = password.len().div_ceil(16).max(1) * 16;
+    let mut hidden = password.to_vec();
+    hidden.resize(padded_len, 0);
+
+    let mut previous: [u8; 16] = *authenticator;
+    for block in hidden.chunks_mut(16) {
+        let mut hasher = Md5::new();
+        hasher.update(secret);
+        hasher.update(previous);
+        let key = hasher.finalize();
+        for (byte, k) in block.iter_mut().zip(key.iter()) {
+            *byte ^= k;
+        }
+        previous.copy_from_slice(block);
+    }
+    hidden
+}
+
+#[async_trait]
+impl Authenticator for RadiusAuthenticator {
+    async fn authenticate(&self, client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        // Truncating would let a longer password in whenever its first 128
+        // bytes are right
+        if password.len() > MAX_PASSWORD_LEN || username.len() > MAX_ATTRIBUTE_LEN {
+            return Err(ServerError::AuthenticationFailed(
+                "Username or password too long for RADIUS".to_string(),
+            ));
+        }
+        let (packet, authenticator) = self.build_request(client, username, password);
+
+        let servers = &self.config.servers;
+        let first = self.preferred.load(Ordering::Relaxed) % servers.len();
+        for index in (0..servers.len()).map(|i| (first + i) % servers.len()) {
+            let server = &servers[index];
+            match self.exchange(server, &packet, &authenticator).await {
+                Ok(code) => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return match code {
+                        ACCESS_ACCEPT => Ok(Identity::user(username)),
+                        ACCESS_REJECT | ACCESS_CHALLENGE => Err(ServerError::AuthenticationFailed(
+                            "Invalid username or password".to_string(),
+                        )),
+                        other => Err(ServerError::AuthBackendUnavailable(format!(
+                            "Unexpected RADIUS response code {}",
+                            other
+                        ))),
+                    };
+                }
+                Err(e) => log::warn!("RADIUS server {} failed: {}", server, e),
+            }
+        }
+
+        Err(ServerError::AuthBackendUnavailable(
+            "No RADIUS server answered".to_string(),
+        ))
+    }
+}
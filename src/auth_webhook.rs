@@ -0,0 +1,131 @@
+use crate::auth::{AuthCache, Authenticator, Identity};
+use crate::errors::{Result, ServerError};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Settings for [`WebhookAuthenticator`].
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Endpoint that receives a JSON POST for every uncached login.
+    pub url: String,
+    /// Deadline for the whole HTTP exchange.
+    pub timeout: Duration,
+    /// How long an accepted login is remembered.
+    pub cache_ttl: Duration,
+    /// How long a rejected login is remembered.
+    pub negative_cache_ttl: Duration,
+    /// Let clients in when the webhook cannot be reached or answers with an error.
+    pub fail_open: bool,
+}
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    client_ip: String,
+}
+
+/// Expected body of a 2xx answer. `identity` optionally overrides the name the
+/// client is known by in logs.
+#[derive(Deserialize)]
+struct WebhookResponse {
+    allow: bool,
+    #[serde(default)]
+    identity: Option<String>,
+}
+
+/// Authenticates credentials by POSTing them to an HTTP endpoint.
+///
+/// The endpoint receives `{"username", "password", "client_ip"}` and answers
+/// with `{"allow": true|false}`. 401 and 403 answers also count as a
+/// rejection; anything else, including timeouts, is handled by the
+/// configured fail-open or fail-closed policy and is never cached.
+pub struct WebhookAuthenticator {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    cache: AuthCache,
+}
+
+impl WebhookAuthenticator {
+    pub fn new(config: WebhookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| ServerError::Unknown(format!("Failed to build webhook client: {}", e)))?;
+        let cache = AuthCache::new(config.cache_ttl, config.negative_cache_ttl);
+        Ok(WebhookAuthenticator {
+            config,
+            client,
+            cache,
+        })
+    }
+
+    /// Returns `Some(identity)` if the webhook allowed the login, `None` if it refused.
+    async fn query(&self, client: SocketAddr, username: &str, password: &str) -> std::result::Result<Option<Identity>, String> {
+        let request = WebhookRequest {
+            username,
+            password,
+            client_ip: client.ip().to_canonical().to_string(),
+        };
+        let response = self
+            .client
+            .post(&self.config.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(format!("unexpected status {}", status));
+        }
+
+        let body: WebhookResponse = response.json().await.map_err(|e| e.to_string())?;
+        if !body.allow {
+            return Ok(None);
+        }
+        let identity = body.identity.as_deref().unwrap_or(username);
+        Ok(Some(Identity::user(identity)))
+    }
+}
+
+#[async_trait]
+impl Authenticator for WebhookAuthenticator {
+    async fn authenticate(&self, client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        let client_ip = client.ip().to_canonical().to_string();
+        let key = AuthCache::key(&[&client_ip, username, password]);
+        if let Some(outcome) = self.cache.get(&key).await {
+            log::debug!("Webhook auth cache hit: user={}, allowed={}", username, outcome.is_ok());
+            return outcome;
+        }
+
+        match self.query(client, username, password).await {
+            Ok(Some(identity)) => {
+                self.cache.allow(key, identity.clone()).await;
+                Ok(identity)
+            }
+            Ok(None) => {
+                self.cache.deny(key).await;
+                Err(ServerError::AuthenticationFailed(
+                    "Invalid username or password".to_string(),
+                ))
+            }
+            Err(e) if self.config.fail_open => {
+                log::warn!("Auth webhook failed, letting {} in (fail-open): {}", username, e);
+                Ok(Identity::user(username))
+            }
+            Err(e) => {
+                log::warn!("Auth webhook failed, rejecting {} (fail-closed): {}", username, e);
+                Err(ServerError::AuthBackendUnavailable(format!(
+                    "Authentication service unavailable: {}",
+                    e
+                )))
+            }
+        }
+    }
+}
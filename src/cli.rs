@@ -1,4 +1,3 @@
-use clap::arg;
 use clap::Parser;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -14,11 +13,17 @@ pub struct CliArgs {
     #[arg(short, long, default_value_t = 1080)]
     pub port: u16,
 
-    /// Enable anonymous access
+    /// Enable anonymous access. Also required for SOCKS4 and SOCKS4a, which
+    /// cannot carry a password
 
     #[arg(short, long, default_value_t = false)]
     pub allow_anonymous: bool,
 
+    /// Disable the legacy SOCKS4 and SOCKS4a protocols. They are only served
+    /// with --allow-anonymous; requests are rejected with code 91 otherwise
+    #[arg(long, default_value_t = false)]
+    pub disable_socks4: bool,
+
     /// Username for authentication
 
     #[arg(short, long)]
@@ -29,21 +34,238 @@ pub struct CliArgs {
     #[arg(short = 'P', long)]
     pub password: Option<String>,
 
+    /// htpasswd-style users file with bcrypt or argon2 hashed passwords
+    #[arg(long)]
+    pub users_file: Option<String>,
+
+    /// Authenticate logins by POSTing them to this URL instead of using local accounts
+    #[arg(long)]
+    pub auth_webhook_url: Option<String>,
+
+    /// Auth webhook request timeout in milliseconds
+    #[arg(long, default_value_t = 3000)]
+    pub auth_webhook_timeout_ms: u64,
+
+    /// Seconds an accepted webhook login is cached
+    #[arg(long, default_value_t = 60)]
+    pub auth_webhook_cache_ttl_secs: u64,
+
+    /// Seconds a rejected webhook login is cached
+    #[arg(long, default_value_t = 10)]
+    pub auth_webhook_negative_cache_ttl_secs: u64,
+
+    /// Let clients in when the auth webhook is unreachable or fails
+    #[arg(long, default_value_t = false)]
+    pub auth_webhook_fail_open: bool,
+
+    /// Authenticate logins against this LDAP server (ldap:// or ldaps://) instead of using local accounts
+    #[arg(long, requires = "ldap_base_dn")]
+    pub ldap_url: Option<String>,
+
+    /// DN of the LDAP service account used to search for users (anonymous search if unset)
+    #[arg(long)]
+    pub ldap_bind_dn: Option<String>,
+
+    /// File containing the password of the LDAP service account
+    #[arg(long)]
+    pub ldap_bind_password_file: Option<String>,
+
+    /// Base DN under which LDAP users are searched
+    #[arg(long)]
+    pub ldap_base_dn: Option<String>,
+
+    /// LDAP search filter for users; {username} is replaced with the login name
+    #[arg(long, default_value = "(uid={username})")]
+    pub ldap_user_filter: String,
+
+    /// DN of an LDAP group users must be a member of
+    #[arg(long)]
+    pub ldap_required_group: Option<String>,
+
+    /// Maximum number of pooled LDAP connections
+    #[arg(long, default_value_t = 4)]
+    pub ldap_pool_size: usize,
+
+    /// LDAP login check timeout in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    pub ldap_timeout_ms: u64,
+
+    /// Seconds an accepted LDAP login is cached
+    #[arg(long, default_value_t = 60)]
+    pub ldap_cache_ttl_secs: u64,
+
+    /// Seconds a rejected LDAP login is cached
+    #[arg(long, default_value_t = 10)]
+    pub ldap_negative_cache_ttl_secs: u64,
+
+    /// Authenticate logins against this RADIUS server (host[:port], repeatable for failover)
+    #[arg(long, requires = "radius_secret_file")]
+    pub radius_server: Vec<String>,
+
+    /// File containing the RADIUS shared secret
+    #[arg(long)]
+    pub radius_secret_file: Option<String>,
+
+    /// Milliseconds to wait for a RADIUS answer before retransmitting
+    #[arg(long, default_value_t = 3000)]
+    pub radius_timeout_ms: u64,
+
+    /// Retransmissions to a RADIUS server before failing over to the next one
+    #[arg(long, default_value_t = 2)]
+    pub radius_retries: u32,
+
+    /// NAS-Identifier sent in RADIUS requests
+    #[arg(long, default_value = "rusk-socks5")]
+    pub radius_nas_identifier: String,
+
+    /// Failed logins from one IP or for one username before it is temporarily banned (0 disables brute-force protection)
+    #[arg(long, default_value_t = 10)]
+    pub auth_max_failures: u32,
+
+    /// Seconds a brute-force ban lasts
+    #[arg(long, default_value_t = 600)]
+    pub auth_ban_secs: u64,
+
+    /// Delay in milliseconds after the first failed login, doubled for each further failure
+    #[arg(long, default_value_t = 250)]
+    pub auth_backoff_base_ms: u64,
+
+    /// Upper bound for the failed-login delay in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    pub auth_backoff_max_ms: u64,
+
+    /// Seconds without failures after which failed-login counts are reset
+    #[arg(long, default_value_t = 900)]
+    pub auth_failure_window_secs: u64,
+
+    /// Also ban usernames after too many failed logins. Lets anyone lock an account out by guessing wrong on purpose
+    #[arg(long, default_value_t = false)]
+    pub auth_ban_usernames: bool,
+
 
 
     /// DNS cache max capacity
     #[arg(long, default_value_t = 10_000)]
     pub dns_cache_capacity: u64,
 
-    /// DNS cache TTL seconds
+    /// DNS cache TTL seconds for answers without a record TTL (system resolver)
     #[arg(long, default_value_t = 300)]
     pub dns_cache_ttl_secs: u64,
 
+    /// Lower bound for cached DNS record TTLs
+    #[arg(long, default_value_t = 0)]
+    pub dns_min_ttl_secs: u64,
+
+    /// Upper bound for cached DNS record TTLs
+    #[arg(long, default_value_t = 86_400)]
+    pub dns_max_ttl_secs: u64,
+
+    /// Seconds a name that does not exist (NXDOMAIN) stays cached
+    #[arg(long, default_value_t = 30)]
+    pub dns_negative_ttl_secs: u64,
+
+    /// Upstream DNS server (IP[:PORT], port defaults to 53). Repeat for round-robin; without it the system resolver is used.
+    #[arg(long)]
+    pub dns_server: Vec<String>,
+
+    /// Milliseconds to wait for one DNS server to answer
+    #[arg(long, default_value_t = 2000)]
+    pub dns_timeout_ms: u64,
+
+    /// DNS queries sent, to successive servers, before a lookup fails
+    #[arg(long, default_value_t = 3)]
+    pub dns_attempts: u32,
+
+    /// Query DNS servers over TCP only instead of UDP with TCP fallback
+    #[arg(long, default_value_t = false)]
+    pub dns_tcp: bool,
+
+    /// DNS-over-TLS server (IP[:PORT][#TLS_NAME], port defaults to 853). Repeatable.
+    #[arg(long)]
+    pub dns_over_tls: Vec<String>,
+
+    /// DNS-over-HTTPS endpoint (https://HOST/PATH#IP, where IP is the address of HOST; not needed when HOST is an IP). Repeatable.
+    #[arg(long)]
+    pub dns_over_https: Vec<String>,
+
+    /// PEM file with the CA certificates trusted for DNS-over-TLS and DNS-over-HTTPS instead of the bundled roots
+    #[arg(long)]
+    pub dns_tls_ca_file: Option<String>,
+
     /// Max concurrent connections
     #[arg(long, default_value_t = 1024)]
     pub max_connections: usize,
 
-    /// Source IP whitelist rules (CIDR or wildcard). Repeat the flag to add multiple rules.
+    /// Seconds a BIND request waits for the inbound connection
+    #[arg(long, default_value_t = 60)]
+    pub bind_timeout_secs: u64,
+
+    /// Milliseconds allowed for connecting to a target, across all its addresses
+    #[arg(long, default_value_t = 10_000)]
+    pub connect_timeout_ms: u64,
+
+    /// Milliseconds before the next address of a target is tried in parallel (Happy Eyeballs)
+    #[arg(long, default_value_t = 250)]
+    pub connect_attempt_delay_ms: u64,
+
+    /// Source IP whitelist rules (IP, CIDR, range or IPv4/IPv6 wildcard). Repeat the flag to add multiple rules.
     #[arg(long, num_args = 1.., value_delimiter = ' ')]
     pub ip_whitelist: Vec<String>,
+
+    /// Source IP blacklist rules (same syntax as the whitelist), checked before the whitelist. Repeat the flag to add multiple rules.
+    #[arg(long, num_args = 1.., value_delimiter = ' ')]
+    pub ip_blacklist: Vec<String>,
+
+    /// File with source IP whitelist rules, one per line (# starts a comment). Repeatable
+    #[arg(long)]
+    pub ip_whitelist_file: Vec<String>,
+
+    /// File with source IP blacklist rules, one per line (# starts a comment). Repeatable
+    #[arg(long)]
+    pub ip_blacklist_file: Vec<String>,
+
+    /// Seconds between checks for changed IP rule files (0: reload only on SIGHUP)
+    #[arg(long, default_value_t = 5)]
+    pub ip_rules_poll_secs: u64,
+
+    /// Destination rule "allow|deny HOST [PORTS]", repeatable, first match wins.
+    /// HOST: *, IP/CIDR, domain, .domain or *.domain, ~regex. PORTS: 443 or 8000-9000, comma-separated
+    #[arg(long)]
+    pub dest_rule: Vec<String>,
+
+    /// Deny destinations that match no --dest-rule (default: allow)
+    #[arg(long, default_value_t = false)]
+    pub dest_default_deny: bool,
+
+    /// Allow connections to loopback, private and link-local destinations
+    #[arg(long, default_value_t = false)]
+    pub disable_ssrf_protection: bool,
+
+    /// Private destination range (IP or CIDR) that stays reachable, repeatable
+    #[arg(long)]
+    pub private_dest_allow: Vec<String>,
+
+    /// MaxMind DB (mmdb) file with country data, e.g. GeoLite2-Country.mmdb
+    #[arg(long)]
+    pub geoip_country_db: Option<String>,
+
+    /// MaxMind DB (mmdb) file with ASN data, e.g. GeoLite2-ASN.mmdb
+    #[arg(long)]
+    pub geoip_asn_db: Option<String>,
+
+    /// Only accept clients matching these GeoIP rules (country:XX or asn:N), repeatable
+    #[arg(long)]
+    pub geo_source_allow: Vec<String>,
+
+    /// Reject clients matching these GeoIP rules (country:XX or asn:N), repeatable
+    #[arg(long)]
+    pub geo_source_deny: Vec<String>,
+
+    /// Only connect to destinations matching these GeoIP rules (country:XX or asn:N), repeatable
+    #[arg(long)]
+    pub geo_dest_allow: Vec<String>,
+
+    /// Refuse destinations matching these GeoIP rules (country:XX or asn:N), repeatable
+    #[arg(long)]
+    pub geo_dest_deny: Vec<String>,
 }
\ No newline at end of file
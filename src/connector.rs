@@ -0,0 +1,243 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Settings for [`Connector`].
+#[derive(Debug, Clone)]
+pub struct ConnectorConfig {
+    /// How long an attempt gets before the next address is tried in
+    /// parallel ("Connection Attempt Delay", RFC 8305 section 5).
+    pub attempt_delay: Duration,
+    /// Deadline for the whole race.
+    pub timeout: Duration,
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        ConnectorConfig {
+            attempt_delay: Duration::from_millis(250),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Opens outbound TCP connections with Happy Eyeballs v2 (RFC 8305).
+///
+/// Addresses are interleaved by family, IPv6 first. A new attempt starts
+/// whenever the previous one fails or has been pending for `attempt_delay`;
+/// the first to connect wins and the others are cancelled. An unresponsive
+/// address therefore costs one attempt delay instead of a full TCP timeout.
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    config: ConnectorConfig,
+}
+
+impl Connector {
+    pub fn new(config: ConnectorConfig) -> Self {
+        Connector { config }
+    }
+
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        self.race(addrs, TcpStream::connect).await
+    }
+
+    /// Runs the race with `connect` making each attempt.
+    async fn race<T, F, Fut>(&self, addrs: &[SocketAddr], connect: F) -> io::Result<T>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut pending = interleave(addrs).into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+        let deadline = tokio::time::sleep(self.config.timeout);
+        tokio::pin!(deadline);
+        let mut next_attempt = Instant::now();
+
+        loop {
+            if attempts.is_empty() && pending.len() == 0 {
+                break;
+            }
+            tokio::select! {
+                _ = &mut deadline => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connect timed out after {:?}", self.config.timeout),
+                    ));
+                }
+                _ = tokio::time::sleep_until(next_attempt), if pending.len() > 0 => {
+                    let addr = pending.next().unwrap();
+                    log::debug!("Connecting to {}", addr);
+                    let attempt = connect(addr);
+                    attempts.spawn(async move { (addr, attempt.await) });
+                    next_attempt = Instant::now() + self.config.attempt_delay;
+                }
+                Some(result) = attempts.join_next() => match result {
+                    // Dropping `attempts` cancels the connections still in progress
+                    Ok((_, Ok(stream))) => return Ok(stream),
+                    Ok((addr, Err(e))) => {
+                        log::debug!("Connect to {} failed: {}", addr, e);
+                        last_err = Some(e);
+                        // No point waiting out the delay after a failure
+                        next_attempt = Instant::now();
+                    }
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                },
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")))
+    }
+}
+
+/// Orders `addrs` IPv6, IPv4, IPv6, ... keeping the order within each family.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.iter().copied().partition(|a| a.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_alternates_families_ipv6_first() {
+        let ordered = interleave(&addrs(&["1.1.1.1:80", "2.2.2.2:80", "[::1]:80", "[::2]:80"]));
+        assert_eq!(ordered, addrs(&["[::1]:80", "1.1.1.1:80", "[::2]:80", "2.2.2.2:80"]));
+    }
+
+    #[test]
+    fn interleave_appends_the_rest_of_the_larger_family() {
+        let ordered = interleave(&addrs(&["[::1]:80", "1.1.1.1:80", "2.2.2.2:80", "3.3.3.3:80"]));
+        assert_eq!(ordered, addrs(&["[::1]:80", "1.1.1.1:80", "2.2.2.2:80", "3.3.3.3:80"]));
+
+        let ordered = interleave(&addrs(&["[::1]:80", "[::2]:80", "[::3]:80", "1.1.1.1:80"]));
+        assert_eq!(ordered, addrs(&["[::1]:80", "1.1.1.1:80", "[::2]:80", "[::3]:80"]));
+    }
+
+    #[test]
+    fn interleave_keeps_a_single_family_in_order() {
+        let list = addrs(&["3.3.3.3:80", "1.1.1.1:80", "2.2.2.2:80"]);
+        assert_eq!(interleave(&list), list);
+        assert!(interleave(&[]).is_empty());
+    }
+
+    /// Counts attempts that were dropped before finishing.
+    struct CancelGuard(Arc<AtomicUsize>, bool);
+
+    impl Drop for CancelGuard {
+        fn drop(&mut self) {
+            if !self.1 {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// An attempt that never completes for `hang`, fails for `refuse` and
+    /// succeeds after 10ms for any other address.
+    fn fake_connect(
+        hang: SocketAddr,
+        refuse: Option<SocketAddr>,
+        cancelled: Arc<AtomicUsize>,
+    ) -> impl Fn(SocketAddr) -> std::pin::Pin<Box<dyn Future<Output = io::Result<SocketAddr>> + Send>> {
+        move |addr| {
+            let cancelled = cancelled.clone();
+            Box::pin(async move {
+                let mut guard = CancelGuard(cancelled, false);
+                if addr == hang {
+                    std::future::pending::<()>().await;
+                }
+                if Some(addr) == refuse {
+                    guard.1 = true;
+                    return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                guard.1 = true;
+                Ok(addr)
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unresponsive_address_costs_one_attempt_delay() {
+        let list = addrs(&["[2001:db8::1]:80", "192.0.2.1:80"]);
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let connector = Connector::default();
+
+        let start = Instant::now();
+        let winner = connector
+            .race(&list, fake_connect(list[0], None, cancelled.clone()))
+            .await
+            .unwrap();
+        assert_eq!(winner, list[1]);
+        assert_eq!(start.elapsed(), Duration::from_millis(260));
+        // The hanging IPv6 attempt was dropped once IPv4 won
+        tokio::task::yield_now().await;
+        assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_attempt_starts_the_next_immediately() {
+        let list = addrs(&["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80"]);
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let connector = Connector::default();
+
+        let start = Instant::now();
+        let winner = connector
+            .race(&list, fake_connect(list[2], Some(list[0]), cancelled))
+            .await
+            .unwrap();
+        assert_eq!(winner, list[1]);
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_at_the_deadline() {
+        let list = addrs(&["192.0.2.1:80"]);
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let connector = Connector::new(ConnectorConfig {
+            attempt_delay: Duration::from_millis(250),
+            timeout: Duration::from_secs(2),
+        });
+
+        let start = Instant::now();
+        let err = connector
+            .race(&list, fake_connect(list[0], None, cancelled.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        tokio::task::yield_now().await;
+        assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_the_last_error_when_every_attempt_fails() {
+        let list = addrs(&["192.0.2.1:80"]);
+        let unused = "192.0.2.2:80".parse().unwrap();
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let err = Connector::default()
+            .race(&list, fake_connect(unused, Some(list[0]), cancelled))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::geoip::GeoFilter;
+use ipnet::IpNet;
+use regex::Regex;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::LazyLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+pub enum HostMatcher {
+    Any,
+    Cidr(IpNet),
+    /// Exact domain name, compared case-insensitively.
+    Domain(String),
+    /// `.example.com` matches example.com and its subdomains,
+    /// `*.example.com` only the subdomains. Stored with the leading dot.
+    Suffix { suffix: String, include_apex: bool },
+    Regex(Regex),
+}
+
+/// One destination rule: `allow|deny HOST [PORTS]`.
+///
+/// HOST is `*`, an IP or CIDR, a domain, `.domain` / `*.domain` for
+/// subdomains, or `~regex` matched against the domain name. PORTS is a
+/// comma-separated list of ports or `start-end` ranges; all ports if omitted.
+#[derive(Debug, Clone)]
+pub struct DestRule {
+    pub action: Action,
+    pub host: HostMatcher,
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl DestRule {
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let mut fields = rule.split_whitespace();
+        let action = match fields.next().map(|a| a.to_ascii_lowercase()).as_deref() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            _ => return Err(format!("Destination rule must start with allow or deny: {}", rule)),
+        };
+        let host = fields
+            .next()
+            .ok_or_else(|| format!("Destination rule without host: {}", rule))?;
+        let host = Self::parse_host(host)?;
+        let ports = match fields.next() {
+            Some(ports) => Self::parse_ports(ports)?,
+            None => Vec::new(),
+        };
+        if fields.next().is_some() {
+            return Err(format!("Trailing fields in destination rule: {}", rule));
+        }
+        Ok(DestRule { action, host, ports })
+    }
+
+    fn parse_host(host: &str) -> Result<HostMatcher, String> {
+        if host == "*" {
+            return Ok(HostMatcher::Any);
+        }
+        if let Some(pattern) = host.strip_prefix('~') {
+            return Regex::new(pattern)
+                .map(HostMatcher::Regex)
+                .map_err(|e| format!("Invalid destination regex {}: {}", pattern, e));
+        }
+        if let Ok(net) = host.parse::<IpNet>() {
+            return Ok(HostMatcher::Cidr(net));
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(HostMatcher::Cidr(IpNet::from(ip)));
+        }
+        let host = normalize_domain(host);
+        if let Some(domain) = host.strip_prefix("*.") {
+            return Ok(HostMatcher::Suffix {
+                suffix: format!(".{}", domain),
+                include_apex: false,
+            });
+        }
+        if host.starts_with('.') {
+            return Ok(HostMatcher::Suffix {
+                suffix: host,
+                include_apex: true,
+            });
+        }
+        if host.is_empty() || host.contains('*') {
+            return Err(format!("Invalid destination host pattern: {}", host));
+        }
+        Ok(HostMatcher::Domain(host))
+    }
+
+    fn parse_ports(ports: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+        ports
+            .split(',')
+            .map(|p| {
+                let range = match p.split_once('-') {
+                    Some((start, end)) => start.parse::<u16>().and_then(|s| Ok(s..=end.parse::<u16>()?)),
+                    None => p.parse::<u16>().map(|port| port..=port),
+                };
+                match range {
+                    Ok(range) if !range.is_empty() => Ok(range),
+                    _ => Err(format!("Invalid port or port range: {}", p)),
+                }
+            })
+            .collect()
+    }
+
+    fn applies_to_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port))
+    }
+
+    /// `domain` is the name the client asked for, if any, and `ip` the
+    /// address being connected to, if known.
+    fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+        if !self.applies_to_port(port) {
+            return false;
+        }
+        match (&self.host, domain, ip) {
+            (HostMatcher::Any, _, _) => true,
+            (HostMatcher::Cidr(net), _, Some(ip)) => net.contains(&ip.to_canonical()),
+            (HostMatcher::Domain(name), Some(domain), _) => *name == normalize_domain(domain),
+            (HostMatcher::Suffix { suffix, include_apex }, Some(domain), _) => {
+                let domain = normalize_domain(domain);
+                domain.ends_with(suffix.as_str()) || (*include_apex && domain == suffix[1..])
+            }
+            (HostMatcher::Regex(re), Some(domain), _) => re.is_match(&normalize_domain(domain)),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DestRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        write!(f, "{} ", action)?;
+        match &self.host {
+            HostMatcher::Any => write!(f, "*")?,
+            HostMatcher::Cidr(net) => write!(f, "{}", net)?,
+            HostMatcher::Domain(name) => write!(f, "{}", name)?,
+            HostMatcher::Suffix { suffix, include_apex: true } => write!(f, "{}", suffix)?,
+            HostMatcher::Suffix { suffix, include_apex: false } => write!(f, "*{}", suffix)?,
+            HostMatcher::Regex(re) => write!(f, "~{}", re)?,
+        }
+        for (i, range) in self.ports.iter().enumerate() {
+            let sep = if i == 0 { " " } else { "," };
+            if range.start() == range.end() {
+                write!(f, "{}{}", sep, range.start())?;
+            } else {
+                write!(f, "{}{}-{}", sep, range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Destinations that are refused unless explicitly allowed: loopback,
+/// private, shared (CGNAT), link-local (including cloud metadata endpoints
+/// such as 169.254.169.254), multicast, reserved, benchmarking,
+/// documentation and unspecified addresses.
+static PRIVATE_RANGES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.0.2.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "64:ff9b:1::/48",
+        "2001:db8::/32",
+        "fc00::/7",
+        "fe80::/10",
+        "fec0::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|range| range.parse().expect("valid private range"))
+    .collect()
+});
+
+fn is_private(ip: &IpAddr) -> bool {
+    let ip = embedded_ipv4(ip);
+    PRIVATE_RANGES.iter().any(|net| net.contains(&ip))
+}
+
+/// Returns the IPv4 address a translated IPv6 address reaches: IPv4-mapped
+/// (`::ffff:a.b.c.d`), NAT64 (`64:ff9b::a.b.c.d`) and 6to4 (`2002:aabb:ccdd::`).
+/// Other addresses are returned unchanged.
+fn embedded_ipv4(ip: &IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            let octets = v6.octets();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                IpAddr::from([octets[12], octets[13], octets[14], octets[15]])
+            } else if segments[0] == 0x2002 {
+                IpAddr::from([octets[2], octets[3], octets[4], octets[5]])
+            } else {
+                IpAddr::V6(v6)
+            }
+        }
+        ip => ip,
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Decides where clients may connect to. Rules are evaluated in order and
+/// the first match wins; destinations no rule matches get the default policy.
+///
+/// Before any rule is consulted, private and loopback addresses are refused
+/// unless they are covered by [`DestFilter::allow_private`] or the check is
+/// turned off. This runs on resolved addresses, so a public name pointing at
+/// an internal address (DNS rebinding) is refused as well.
+#[derive(Debug, Clone)]
+pub struct DestFilter {
+    rules: Vec<DestRule>,
+    default: Action,
+    block_private: bool,
+    private_exceptions: Vec<IpNet>,
+    geo: Option<GeoFilter>,
+}
+
+impl Default for DestFilter {
+    fn default() -> Self {
+        Self::new(Action::Allow)
+    }
+}
+
+impl DestFilter {
+    pub fn new(default: Action) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+            block_private: true,
+            private_exceptions: Vec::new(),
+            geo: None,
+        }
+    }
+
+    /// Checks resolved addresses against GeoIP rules, after the private
+    /// address check and before the destination rules.
+    pub fn set_geo_filter(&mut self, geo: GeoFilter) {
+        self.geo = Some(geo);
+    }
+
+    /// Turns the private and loopback address check on or off (on by default).
+    pub fn set_block_private(&mut self, block: bool) {
+        self.block_private = block;
+    }
+
+    /// Exempts a private range, e.g. an internal service clients must reach.
+    pub fn allow_private(&mut self, pattern: &str) -> Result<(), String> {
+        let net = match pattern.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => pattern
+                .parse::<IpAddr>()
+                .map(IpNet::from)
+                .map_err(|_| format!("Invalid private destination exception: {}", pattern))?,
+        };
+        self.private_exceptions.push(net);
+        Ok(())
+    }
+
+    pub fn from_strings(rules: &[String], default: Action) -> Result<Self, String> {
+        let mut filter = Self::new(default);
+        for rule in rules {
+            filter.add_rule(rule)?;
+        }
+        Ok(filter)
+    }
+
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), String> {
+        self.rules.push(DestRule::parse(rule)?);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a request for `domain`:`port` is denied by the rules whatever
+    /// the name resolves to, so that it can be refused without a DNS lookup.
+    /// False when a CIDR rule could decide first.
+    pub fn denies_domain(&self, domain: &str, port: u16) -> bool {
+        for rule in &self.rules {
+            if let HostMatcher::Cidr(_) = rule.host {
+                if rule.applies_to_port(port) {
+                    return false;
+                }
+                continue;
+            }
+            if rule.matches(Some(domain), None, port) {
+                if rule.action == Action::Deny {
+                    log::debug!("Destination {} port {} matched rule \"{}\"", domain, port, rule);
+                }
+                return rule.action == Action::Deny;
+            }
+        }
+        self.default == Action::Deny
+    }
+
+    /// Checks a connection to `ip`:`port`, made on behalf of a client that
+    /// asked for `domain` (`None` for IP-literal requests). Domain rules match
+    /// the requested name, CIDR rules the address it resolved to.
+    pub fn allows(&self, domain: Option<&str>, ip: IpAddr, port: u16) -> bool {
+        let canonical = embedded_ipv4(&ip);
+        if self.block_private
+            && is_private(&canonical)
+            && !self.private_exceptions.iter().any(|net| net.contains(&canonical))
+        {
+            log::warn!(
+                "Refusing private destination {}{} port {}",
+                domain.map(|d| format!("{} ", d)).unwrap_or_default(),
+                ip,
+                port
+            );
+            return false;
+        }
+        if let Some(geo) = &self.geo
+            && !geo.allows(&canonical)
+        {
+            log::warn!(
+                "Refusing destination {}{} port {} by GeoIP rules",
+                domain.map(|d| format!("{} ", d)).unwrap_or_default(),
+                ip,
+                port
+            );
+            return false;
+        }
+        match self.rules.iter().find(|r| r.matches(domain, Some(ip), port)) {
+            Some(rule) => {
+                log::debug!(
+                    "Destination {}{} port {} matched rule \"{}\"",
+                    domain.map(|d| format!("{} ", d)).unwrap_or_default(),
+                    ip,
+                    port,
+                    rule
+                );
+                rule.action == Action::Allow
+            }
+            None => self.default == Action::Allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(ip: &str) -> bool {
+        is_private(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_non_public_ranges() {
+        for ip in [
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "192.0.0.8",
+            "192.0.2.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "198.51.100.7",
+            "203.0.113.9",
+            "ff02::1",
+            "fec0::1",
+            "2001:db8::1",
+        ] {
+            assert!(private(ip), "{} should be private", ip);
+        }
+        for ip in ["8.8.8.8", "198.20.0.1", "2606:4700::1111"] {
+            assert!(!private(ip), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn checks_ipv4_mapped_addresses() {
+        assert!(private("::ffff:127.0.0.1"));
+        assert!(private("::ffff:169.254.169.254"));
+        assert!(!private("::ffff:8.8.8.8"));
+    }
+
+    #[test]
+    fn checks_ipv4_behind_translation_prefixes() {
+        assert!(private("64:ff9b::127.0.0.1"));
+        assert!(private("64:ff9b::a9fe:a9fe"));
+        assert!(private("64:ff9b::10.1.2.3"));
+        assert!(!private("64:ff9b::8.8.8.8"));
+        assert!(private("2002:7f00:1::1"));
+        assert!(private("2002:c0a8:101::"));
+        assert!(!private("2002:808:808::1"));
+    }
+
+    #[test]
+    fn exceptions_match_the_embedded_address() {
+        let mut filter = DestFilter::default();
+        assert!(!filter.allows(None, "64:ff9b::10.0.0.5".parse().unwrap(), 80));
+        filter.allow_private("10.0.0.0/24").unwrap();
+        assert!(filter.allows(None, "64:ff9b::10.0.0.5".parse().unwrap(), 80));
+        assert!(filter.allows(None, "::ffff:10.0.0.5".parse().unwrap(), 80));
+    }
+
+    fn rules(rules: &[&str], default: Action) -> DestFilter {
+        let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        DestFilter::from_strings(&rules, default).unwrap()
+    }
+
+    #[test]
+    fn denies_domains_before_resolving() {
+        let filter = rules(&["deny .example.com", "allow * 443", "deny ~^ads\\."], Action::Allow);
+        assert!(filter.denies_domain("www.EXAMPLE.com.", 443));
+        assert!(!filter.denies_domain("example.org", 443));
+        assert!(filter.denies_domain("ads.example.org", 80));
+        assert!(!filter.denies_domain("ads.example.org", 443));
+        assert!(!filter.denies_domain("example.org", 80));
+
+        let deny_by_default = rules(&["allow example.org"], Action::Deny);
+        assert!(!deny_by_default.denies_domain("example.org", 80));
+        assert!(deny_by_default.denies_domain("example.net", 80));
+    }
+
+    #[test]
+    fn cidr_rules_leave_the_domain_check_to_after_resolution() {
+        // The address could match the earlier CIDR rule
+        let filter = rules(&["allow 8.8.8.0/24", "deny .example.com"], Action::Allow);
+        assert!(!filter.denies_domain("www.example.com", 80));
+        assert!(filter.allows(Some("www.example.com"), "8.8.8.8".parse().unwrap(), 80));
+        assert!(!filter.allows(Some("www.example.com"), "1.1.1.1".parse().unwrap(), 80));
+
+        // Unless it is for other ports
+        let filter = rules(&["allow 8.8.8.0/24 22", "deny .example.com"], Action::Allow);
+        assert!(filter.denies_domain("www.example.com", 80));
+        assert!(!filter.denies_domain("www.example.com", 22));
+
+        let filter = rules(&["allow 8.8.8.0/24"], Action::Deny);
+        assert!(!filter.denies_domain("www.example.com", 80));
+    }
+}
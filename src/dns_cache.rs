@@ -1,38 +1,139 @@
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use moka::Expiry;
 use moka::future::Cache;
+use crate::errors::ServerError;
+use crate::resolver::{Resolver, SystemResolver};
+
+/// Capacity and expiry settings for [`DnsCache`].
+#[derive(Debug, Clone)]
+pub struct DnsCacheConfig {
+    pub max_capacity: u64,
+    /// Used when the resolver does not report a TTL (the system resolver).
+    pub default_ttl: Duration,
+    /// Record TTLs are raised to at least this.
+    pub min_ttl: Duration,
+    /// Record TTLs are capped at this.
+    pub max_ttl: Duration,
+    /// How long a name that does not exist is remembered.
+    pub negative_ttl: Duration,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        DnsCacheConfig {
+            max_capacity: 10_000,
+            default_ttl: Duration::from_secs(300),
+            min_ttl: Duration::from_secs(0),
+            max_ttl: Duration::from_secs(86_400),
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    /// Empty for a name that does not exist.
+    addrs: Vec<IpAddr>,
+    ttl: Duration,
+}
+
+/// Expires every entry after its own TTL.
+struct EntryExpiry;
+
+impl Expiry<String, Entry> for EntryExpiry {
+    fn expire_after_create(&self, _key: &String, entry: &Entry, _created_at: Instant) -> Option<Duration> {
+        Some(entry.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        entry: &Entry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(entry.ttl)
+    }
+}
 
 pub struct DnsCache {
-    cache: Cache<String, Vec<SocketAddr>>,
+    cache: Cache<String, Entry>,
+    config: DnsCacheConfig,
+    resolver: Arc<dyn Resolver>,
 }
 
 impl DnsCache {
     pub fn new_default() -> Self {
-        Self::new(10_000, Duration::from_secs(300))
+        Self::with_config(DnsCacheConfig::default(), Arc::new(SystemResolver))
     }
 
+    /// A cache in front of the system resolver that keeps every entry for `ttl`.
     pub fn new(max_capacity: u64, ttl: Duration) -> Self {
+        Self::with_resolver(max_capacity, ttl, Arc::new(SystemResolver))
+    }
+
+    /// A cache in front of `resolver` that keeps every entry for `ttl`,
+    /// whatever TTL its records have.
+    pub fn with_resolver(max_capacity: u64, ttl: Duration, resolver: Arc<dyn Resolver>) -> Self {
+        let config = DnsCacheConfig {
+            max_capacity,
+            default_ttl: ttl,
+            min_ttl: ttl,
+            max_ttl: ttl,
+            ..DnsCacheConfig::default()
+        };
+        Self::with_config(config, resolver)
+    }
+
+    pub fn with_config(config: DnsCacheConfig, resolver: Arc<dyn Resolver>) -> Self {
         let cache = Cache::builder()
-            .max_capacity(max_capacity)
-            .time_to_live(ttl)
+            .max_capacity(config.max_capacity)
+            .expire_after(EntryExpiry)
             .build();
-        Self { cache }
+        Self { cache, config, resolver }
     }
 
     pub async fn resolve(&self, host: &str, port: u16) -> crate::errors::Result<Vec<SocketAddr>> {
-        let key = format!("{}:{}", host, port);
-        if let Some(v) = self.cache.get(&key).await {
-            log::debug!("DNS cache hit: key={}, addrs={}", key, v.len());
-            return Ok(v);
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
         }
-        log::debug!("DNS cache miss: key={}, resolving...", key);
-        let lookup_host = format!("{}:{}", host, port);
-        let res = tokio::net::lookup_host(lookup_host)
+        let key = host.to_ascii_lowercase();
+        // Concurrent misses for the same name wait for a single lookup and
+        // all get its result, including its error
+        let entry = self
+            .cache
+            .entry(key.clone())
+            .or_try_insert_with(self.lookup(&key))
             .await
-            .map_err(|e| crate::errors::ServerError::ConnectionError(e.to_string()))?;
-        let addrs: Vec<SocketAddr> = res.collect();
-        log::debug!("DNS cache insert: key={}, addrs={}", key, addrs.len());
-        self.cache.insert(key, addrs.clone()).await;
-        Ok(addrs)
+            .map_err(|e| match &*e {
+                ServerError::HostUnreachable(message) => ServerError::HostUnreachable(message.clone()),
+                e => ServerError::HostUnreachable(e.to_string()),
+            })?;
+        if !entry.is_fresh() {
+            log::debug!("DNS cache hit: key={}, addrs={}", key, entry.value().addrs.len());
+        }
+        let entry = entry.into_value();
+
+        if entry.addrs.is_empty() {
+            return Err(ServerError::HostUnreachable(format!("{}: no such domain", host)));
+        }
+        Ok(entry.addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    async fn lookup(&self, key: &str) -> crate::errors::Result<Entry> {
+        log::debug!("DNS cache miss: key={}, resolving...", key);
+        let lookup = self.resolver.lookup(key).await?;
+        let ttl = if lookup.addrs.is_empty() {
+            self.config.negative_ttl
+        } else {
+            lookup
+                .ttl
+                .unwrap_or(self.config.default_ttl)
+                .clamp(self.config.min_ttl, self.config.max_ttl.max(self.config.min_ttl))
+        };
+        log::debug!("DNS cache insert: key={}, addrs={}, ttl={:?}", key, lookup.addrs.len(), ttl);
+        Ok(Entry { addrs: lookup.addrs, ttl })
     }
 }
@@ -0,0 +1,257 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+/// The parts of a DNS response (RFC 1035) the resolver cares about.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub rcode: u8,
+    /// Set when the answer did not fit in a UDP datagram.
+    pub truncated: bool,
+    pub addrs: Vec<IpAddr>,
+    /// Lowest TTL among the answer records, in seconds.
+    pub ttl: Option<u32>,
+}
+
+/// Encodes a recursive query for `qtype` records of `name`.
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // QDCOUNT=1, no other sections
+
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(format!("invalid domain name: {:?}", name));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid domain name: {:?}", name));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Parses a response to `query`, checking that it answers the same ID and
+/// question.
+pub fn parse_response(buf: &[u8], query: &[u8]) -> Result<Response, String> {
+    if buf.len() < HEADER_LEN {
+        return Err("short DNS response".to_string());
+    }
+    let flags = u16::from_be_bytes([buf[2], buf[3]]);
+    if buf[..2] != query[..2] || flags & FLAG_QR == 0 {
+        return Err("DNS response does not match the query".to_string());
+    }
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+    let ancount = u16::from_be_bytes([buf[6], buf[7]]);
+    let question = &query[HEADER_LEN..];
+    if qdcount != 1 || !buf[HEADER_LEN..].starts_with(question) {
+        return Err("DNS response is for a different question".to_string());
+    }
+
+    let mut response = Response {
+        rcode: (flags & 0x000f) as u8,
+        truncated: flags & FLAG_TC != 0,
+        addrs: Vec::new(),
+        ttl: None,
+    };
+    if response.truncated {
+        return Ok(response);
+    }
+
+    let mut offset = HEADER_LEN + question.len();
+    for _ in 0..ancount {
+        offset = skip_name(buf, offset)?;
+        let fixed = buf
+            .get(offset..offset + 10)
+            .ok_or("truncated DNS record")?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        offset += 10;
+        let rdata = buf
+            .get(offset..offset + rdlength)
+            .ok_or("truncated DNS record data")?;
+        offset += rdlength;
+        // CNAMEs count too: the answer is only valid as long as the alias is
+        response.ttl = Some(response.ttl.map_or(ttl, |t| t.min(ttl)));
+
+        // CNAME records are otherwise skipped: recursive servers also return
+        // the address records of the target
+        match (rtype, class, rdata.len()) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets: [u8; 4] = rdata.try_into().unwrap();
+                response.addrs.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                response.addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok(response)
+}
+
+/// Returns the offset just past the (possibly compressed) name at `offset`.
+fn skip_name(buf: &[u8], mut offset: usize) -> Result<usize, String> {
+    loop {
+        let len = *buf.get(offset).ok_or("truncated DNS name")?;
+        match len {
+            0 => return Ok(offset + 1),
+            // A compression pointer ends the name
+            l if l & 0xc0 == 0xc0 => return Ok(offset + 2),
+            l if l & 0xc0 == 0 => offset += 1 + l as usize,
+            _ => return Err("invalid DNS label".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    /// A response to `query` with `flags` and answers of (type, ttl, rdata),
+    /// each named by a pointer to the question.
+    fn response(query: &[u8], flags: u16, answers: &[(u16, u32, &[u8])]) -> Vec<u8> {
+        let mut buf = query[..2].to_vec();
+        buf.extend_from_slice(&(FLAG_QR | FLAG_RD | flags).to_be_bytes());
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(&query[HEADER_LEN..]);
+        for (rtype, ttl, rdata) in answers {
+            buf.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+            buf.extend_from_slice(&ttl.to_be_bytes());
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(rdata);
+        }
+        buf
+    }
+
+    fn query() -> Vec<u8> {
+        build_query(0x1234, "example.com.", TYPE_A).unwrap()
+    }
+
+    #[test]
+    fn builds_queries() {
+        let query = query();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[HEADER_LEN..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        assert!(build_query(1, "", TYPE_A).is_err());
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+        assert!(build_query(1, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn parses_addresses_and_lowest_ttl() {
+        let query = query();
+        let cname = b"\x03www\xc0\x0c";
+        let v6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        let buf = response(
+            &query,
+            0,
+            &[(TYPE_CNAME, 30, cname), (TYPE_A, 300, &[192, 0, 2, 1]), (TYPE_AAAA, 60, &v6)],
+        );
+        let response = parse_response(&buf, &query).unwrap();
+        assert_eq!(response.rcode, RCODE_NOERROR);
+        assert!(!response.truncated);
+        assert_eq!(response.addrs, vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "2001:db8::1".parse().unwrap()]);
+        assert_eq!(response.ttl, Some(30));
+    }
+
+    #[test]
+    fn reports_nxdomain() {
+        let query = query();
+        let response = parse_response(&response(&query, RCODE_NXDOMAIN as u16, &[]), &query).unwrap();
+        assert_eq!(response.rcode, RCODE_NXDOMAIN);
+        assert!(response.addrs.is_empty());
+        assert_eq!(response.ttl, None);
+    }
+
+    #[test]
+    fn stops_at_the_tc_bit() {
+        let query = query();
+        // The answer section of a truncated response is not read at all
+        let mut buf = response(&query, FLAG_TC, &[(TYPE_A, 300, &[192, 0, 2, 1])]);
+        buf.truncate(buf.len() - 6);
+        let response = parse_response(&buf, &query).unwrap();
+        assert!(response.truncated);
+        assert!(response.addrs.is_empty());
+    }
+
+    #[test]
+    fn rejects_other_ids_and_questions() {
+        let query = query();
+        let mut buf = response(&query, 0, &[(TYPE_A, 300, &[192, 0, 2, 1])]);
+        buf[1] ^= 1;
+        assert!(parse_response(&buf, &query).is_err());
+
+        let other = build_query(0x1234, "example.org", TYPE_A).unwrap();
+        let buf = response(&other, 0, &[(TYPE_A, 300, &[192, 0, 2, 1])]);
+        assert!(parse_response(&buf, &query).is_err());
+
+        let aaaa = build_query(0x1234, "example.com", TYPE_AAAA).unwrap();
+        let buf = response(&aaaa, 0, &[]);
+        assert!(parse_response(&buf, &query).is_err());
+
+        // A query echoed back without the QR bit is not an answer
+        assert!(parse_response(&query, &query).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let query = query();
+        let buf = response(&query, 0, &[(TYPE_A, 300, &[192, 0, 2, 1])]);
+        let answer_start = query.len();
+        for len in [HEADER_LEN - 1, answer_start + 1, answer_start + 5, buf.len() - 1] {
+            assert!(parse_response(&buf[..len], &query).is_err(), "length {}", len);
+        }
+
+        // More answers announced than present
+        let mut buf = buf;
+        buf[7] = 2;
+        assert!(parse_response(&buf, &query).is_err());
+    }
+
+    #[test]
+    fn does_not_follow_compression_pointer_loops() {
+        let query = query();
+        let mut buf = response(&query, 0, &[]);
+        buf[7] = 1;
+        // A name pointing at itself, then an A record
+        let offset = buf.len() as u8;
+        buf.extend_from_slice(&[0xc0, offset]);
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        let response = parse_response(&buf, &query).unwrap();
+        assert_eq!(response.addrs, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        let query = query();
+        let mut buf = response(&query, 0, &[(TYPE_A, 300, &[192, 0, 2, 1])]);
+        buf[query.len()] = 0x40;
+        assert!(parse_response(&buf, &query).is_err());
+    }
+}
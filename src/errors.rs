@@ -1,4 +1,5 @@
 
+use crate::handlers::Reply;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -22,14 +23,111 @@ pub enum ServerError {
     #[error("Unsupported command: {0}")]
     UnsupportedCmd(u8),
 
+    #[error("Unsupported address type: {0}")]
+    AddressTypeNotSupported(u8),
+
+    #[error("Connection not allowed by ruleset: {0}")]
+    ConnectionNotAllowed(String),
+
+    #[error("Network unreachable: {0}")]
+    NetworkUnreachable(String),
+
+    #[error("Host unreachable: {0}")]
+    HostUnreachable(String),
+
+    #[error("Connection refused: {0}")]
+    ConnectionRefused(String),
+
+    #[error("TTL expired: {0}")]
+    TtlExpired(String),
+
     #[error("Authentication failed: {0}")]
     AuthenticationFailed(String),
 
+    /// The authentication backend could not give an answer, so nothing is
+    /// known about the credentials.
+    #[error("Authentication backend unavailable: {0}")]
+    AuthBackendUnavailable(String),
+
     #[error("Unknown error occurred: {0}")]
     Unknown(String),
 
 
 }
 
+impl ServerError {
+    /// Maps an error from an outbound connect to the variant for its REP code.
+    pub fn from_connect_error(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionRefused => ServerError::ConnectionRefused(e.to_string()),
+            ErrorKind::NetworkUnreachable => ServerError::NetworkUnreachable(e.to_string()),
+            // TTL expired (0x06) is about the packet's TTL, not a connect
+            // timeout: report the host as unreachable
+            ErrorKind::HostUnreachable | ErrorKind::AddrNotAvailable | ErrorKind::TimedOut => {
+                ServerError::HostUnreachable(e.to_string())
+            }
+            ErrorKind::PermissionDenied => ServerError::ConnectionNotAllowed(e.to_string()),
+            _ => ServerError::ConnectionError(e.to_string()),
+        }
+    }
 
-pub type Result<T> = std::result::Result<T, ServerError>;
\ No newline at end of file
+    /// The SOCKS5 REP code reported to the client for this error.
+    pub fn reply(&self) -> Reply {
+        match self {
+            ServerError::ConnectionNotAllowed(_) => Reply::ConnectionNotAllowed,
+            ServerError::NetworkUnreachable(_) => Reply::NetworkUnreachable,
+            ServerError::HostUnreachable(_) => Reply::HostUnreachable,
+            ServerError::ConnectionRefused(_) => Reply::ConnectionRefused,
+            ServerError::TtlExpired(_) => Reply::TtlExpired,
+            ServerError::UnsupportedCmd(_) => Reply::CommandNotSupported,
+            ServerError::AddressTypeNotSupported(_) => Reply::AddressTypeNotSupported,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+
+pub type Result<T> = std::result::Result<T, ServerError>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn errors_map_to_rep_codes() {
+        let cases = [
+            (ServerError::ConnectionNotAllowed(String::new()), 0x02),
+            (ServerError::NetworkUnreachable(String::new()), 0x03),
+            (ServerError::HostUnreachable(String::new()), 0x04),
+            (ServerError::ConnectionRefused(String::new()), 0x05),
+            (ServerError::TtlExpired(String::new()), 0x06),
+            (ServerError::UnsupportedCmd(9), 0x07),
+            (ServerError::AddressTypeNotSupported(5), 0x08),
+            (ServerError::ConnectionError(String::new()), 0x01),
+            (ServerError::BindError(String::new()), 0x01),
+            (ServerError::InvalidRequestFormat, 0x01),
+            (ServerError::AuthenticationFailed(String::new()), 0x01),
+        ];
+        for (err, code) in cases {
+            assert_eq!(u8::from(err.reply()), code, "{}", err);
+        }
+    }
+
+    #[test]
+    fn connect_errors_map_by_kind() {
+        let cases = [
+            (ErrorKind::ConnectionRefused, Reply::ConnectionRefused),
+            (ErrorKind::NetworkUnreachable, Reply::NetworkUnreachable),
+            (ErrorKind::HostUnreachable, Reply::HostUnreachable),
+            (ErrorKind::AddrNotAvailable, Reply::HostUnreachable),
+            (ErrorKind::TimedOut, Reply::HostUnreachable),
+            (ErrorKind::PermissionDenied, Reply::ConnectionNotAllowed),
+            (ErrorKind::ConnectionReset, Reply::GeneralFailure),
+        ];
+        for (kind, reply) in cases {
+            let err = ServerError::from_connect_error(Error::from(kind));
+            assert_eq!(err.reply(), reply, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use maxminddb::{PathElement, Reader};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// A country (`country:CN`, ISO 3166-1 alpha-2) or autonomous system
+/// (`asn:13335` or `asn:AS13335`) rule. Both are case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoRule {
+    Country(String),
+    Asn(u32),
+}
+
+impl GeoRule {
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid GeoIP rule (expected country:XX or asn:N): {}", rule);
+        let (kind, value) = rule.split_once(':').ok_or_else(invalid)?;
+        match kind.to_ascii_lowercase().as_str() {
+            "country" if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) => {
+                Ok(GeoRule::Country(value.to_ascii_uppercase()))
+            }
+            "asn" => value
+                .get(..2)
+                .filter(|prefix| prefix.eq_ignore_ascii_case("as"))
+                .map_or(value, |_| &value[2..])
+                .parse()
+                .map(GeoRule::Asn)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for GeoRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoRule::Country(code) => write!(f, "country:{}", code),
+            GeoRule::Asn(asn) => write!(f, "asn:{}", asn),
+        }
+    }
+}
+
+/// Country and ASN databases in MaxMind DB (mmdb) format, e.g. GeoLite2-Country
+/// or GeoLite2-City and GeoLite2-ASN.
+pub struct GeoIpDb {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl fmt::Debug for GeoIpDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIpDb")
+            .field("country", &self.country.as_ref().map(|r| &r.metadata().database_type))
+            .field("asn", &self.asn.as_ref().map(|r| &r.metadata().database_type))
+            .finish()
+    }
+}
+
+impl GeoIpDb {
+    pub fn open(country_path: Option<&str>, asn_path: Option<&str>) -> Result<Self, String> {
+        let open = |path: &str| {
+            Reader::open_readfile(path)
+                .map_err(|e| format!("Failed to open GeoIP database {}: {}", path, e))
+        };
+        Ok(GeoIpDb {
+            country: country_path.map(open).transpose()?,
+            asn: asn_path.map(open).transpose()?,
+        })
+    }
+
+    /// ISO country code of `ip`, if the country database knows it.
+    pub fn country(&self, ip: &IpAddr) -> Option<String> {
+        let reader = self.country.as_ref()?;
+        let path = [PathElement::Key("country"), PathElement::Key("iso_code")];
+        reader
+            .lookup(ip.to_canonical())
+            .and_then(|result| result.decode_path::<String>(&path))
+            .ok()
+            .flatten()
+    }
+
+    /// Autonomous system number of `ip`, if the ASN database knows it.
+    pub fn asn(&self, ip: &IpAddr) -> Option<u32> {
+        let reader = self.asn.as_ref()?;
+        let path = [PathElement::Key("autonomous_system_number")];
+        reader
+            .lookup(ip.to_canonical())
+            .and_then(|result| result.decode_path::<u32>(&path))
+            .ok()
+            .flatten()
+    }
+}
+
+/// Allow and deny lists of [`GeoRule`]s, with the same precedence as
+/// `IpFilter`: a denied address is always rejected, and an empty allow list
+/// allows everything not denied. Addresses missing from the databases, such
+/// as private ones, match no rule.
+#[derive(Debug, Clone)]
+pub struct GeoFilter {
+    db: Arc<GeoIpDb>,
+    allow: Vec<GeoRule>,
+    deny: Vec<GeoRule>,
+}
+
+impl GeoFilter {
+    pub fn new(db: Arc<GeoIpDb>, allow: &[String], deny: &[String]) -> Result<Self, String> {
+        let parse = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|r| GeoRule::parse(r))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let filter = GeoFilter {
+            db,
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        };
+        for rule in filter.allow.iter().chain(&filter.deny) {
+            let missing = match rule {
+                GeoRule::Country(_) => filter.db.country.is_none().then_some("country"),
+                GeoRule::Asn(_) => filter.db.asn.is_none().then_some("ASN"),
+            };
+            if let Some(kind) = missing {
+                return Err(format!("GeoIP rule {} needs a {} database", rule, kind));
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let rules = || self.allow.iter().chain(&self.deny);
+        let country = rules()
+            .any(|r| matches!(r, GeoRule::Country(_)))
+            .then(|| self.db.country(ip))
+            .flatten();
+        let asn = rules()
+            .any(|r| matches!(r, GeoRule::Asn(_)))
+            .then(|| self.db.asn(ip))
+            .flatten();
+        let matches = |rule: &GeoRule| match rule {
+            GeoRule::Country(code) => country.as_deref() == Some(code.as_str()),
+            GeoRule::Asn(number) => asn == Some(*number),
+        };
+
+        if let Some(rule) = self.deny.iter().find(|r| matches(r)) {
+            log::debug!("{} matched GeoIP deny rule {}", ip, rule);
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(matches)
+    }
+}
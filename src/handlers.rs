@@ -1,14 +1,43 @@
+use crate::connector::Connector;
+use crate::dest_filter::DestFilter;
 use crate::dns_cache::DnsCache;
 use crate::server::ServerConfig;
+use crate::auth::{Authenticator, Identity};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
 pub struct ConnectionHandler {
     socket: tokio::net::TcpStream,
-    _address: SocketAddr,
+    address: SocketAddr,
     config: Arc<ServerConfig>,
     dns_cache: Arc<DnsCache>,
+    dest_filter: Arc<DestFilter>,
+    connector: Arc<Connector>,
+    authenticator: Arc<dyn Authenticator>,
+    identity: Option<Identity>,
+    protocol: Protocol,
+}
+
+/// Protocol spoken by the client, detected from the first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// SOCKS4 and its SOCKS4a domain-name extension.
+    Socks4,
+    Socks5,
+}
+
+/// Result codes (CD field) of a SOCKS4 reply.
+#[derive(Debug, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum Socks4Reply {
+    Granted = 90,
+    Rejected = 91,
+    IdentdUnreachable = 92,
+    IdentdMismatch = 93,
 }
 
 #[derive(Debug, TryFromPrimitive, IntoPrimitive)]
@@ -34,35 +63,123 @@ pub enum Command {
     UDPAssociate = 0x03,
 }
 
+/// Reply codes (REP field) from RFC 1928.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    ConnectionNotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// Destination requested by a client, as carried in DST.ADDR/DST.PORT.
+#[derive(Debug, Clone)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(_)) => AddressType::IPv4,
+            TargetAddr::Ip(SocketAddr::V6(_)) => AddressType::IPv6,
+            TargetAddr::Domain(..) => AddressType::DomainName,
+        }
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+/// Appends ATYP, ADDR and PORT for `addr` in SOCKS5 wire format.
+/// IPv4-mapped IPv6 addresses are written as plain IPv4.
+pub fn encode_socket_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(v4) => {
+            buf.push(AddressType::IPv4.into());
+            buf.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            buf.push(AddressType::IPv6.into());
+            buf.extend_from_slice(&v6.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Builds a SOCKS5 reply with the given REP code and BND.ADDR/BND.PORT.
+pub fn encode_reply(rep: Reply, bound: &SocketAddr) -> Vec<u8> {
+    let mut buf = vec![5, rep.into(), 0];
+    encode_socket_addr(&mut buf, bound);
+    buf
+}
+
+/// Builds a SOCKS4 reply. Every failure is reported as "rejected or failed",
+/// and IPv6 bound addresses, which SOCKS4 cannot carry, are sent as zeros.
+pub fn encode_socks4_reply(rep: Reply, bound: &SocketAddr) -> Vec<u8> {
+    let code = match rep {
+        Reply::Succeeded => Socks4Reply::Granted,
+        _ => Socks4Reply::Rejected,
+    };
+    let ip = match bound.ip().to_canonical() {
+        IpAddr::V4(v4) => v4,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let mut buf = vec![0, code.into()];
+    buf.extend_from_slice(&bound.port().to_be_bytes());
+    buf.extend_from_slice(&ip.octets());
+    buf
+}
+
 impl ConnectionHandler {
-    pub fn new(socket: tokio::net::TcpStream, address: SocketAddr, config: Arc<ServerConfig>, dns_cache: Arc<DnsCache>) -> Self {
+    pub fn new(socket: tokio::net::TcpStream, address: SocketAddr, config: Arc<ServerConfig>, dns_cache: Arc<DnsCache>, dest_filter: Arc<DestFilter>, connector: Arc<Connector>, authenticator: Arc<dyn Authenticator>) -> Self {
         ConnectionHandler {
             socket,
-            _address: address,
+            address,
             config,
             dns_cache,
+            dest_filter,
+            connector,
+            authenticator,
+            identity: None,
+            protocol: Protocol::Socks5,
         }
     }
 
     pub async fn handle(&mut self) -> crate::errors::Result<()> {
         let version = self.socket.read_u8().await?;
 
-        if version != 5 {
-            return Err(crate::errors::ServerError::UnsupportedProtocolVersion);
+        match version {
+            5 => self.handle_socks5().await,
+            4 => {
+                self.protocol = Protocol::Socks4;
+                self.handle_socks4().await
+            }
+            _ => Err(crate::errors::ServerError::UnsupportedProtocolVersion),
         }
+    }
 
+    async fn handle_socks5(&mut self) -> crate::errors::Result<()> {
         let num_methods = self.socket.read_u8().await? as usize;
 
         let mut methods = vec![0u8; num_methods];
 
         self.socket.read_exact(&mut methods).await?;
 
-        if methods
-            .iter()
-            .any(|&x| x == AuthMethod::UsernamePassword as u8)
-            && self.config.username.is_some()
-            && self.config.password.is_some()
-        {
+        if methods.contains(&(AuthMethod::UsernamePassword as u8)) && self.authenticator.accepts_credentials() {
             // Respond with the Username/Password method
             self.socket
                 .write_all(&[5, AuthMethod::UsernamePassword as u8])
@@ -82,25 +199,30 @@ impl ConnectionHandler {
             let password = String::from_utf8(password)
                 .map_err(|_| crate::errors::ServerError::InvalidRequestFormat)?;
 
-            if username.eq(self.config.username.as_ref().unwrap())
-                && password.eq(self.config.password.as_ref().unwrap())
+            match self
+                .authenticator
+                .authenticate(self.address, &username, &password)
+                .await
             {
-                // Authentication successful
-                self.socket.write_all(&[5, 0]).await?; // 0 means success
-            } else {
-                // Authentication failed
-                self.socket.write_all(&[5, 1]).await?; // 1 means failure
-                return Err(crate::errors::ServerError::AuthenticationFailed(
-                    "Invalid username or password".to_string(),
-                ));
+                Ok(identity) => {
+                    // Authentication successful
+                    self.socket.write_all(&[5, 0]).await?; // 0 means success
+                    self.identity = Some(identity);
+                }
+                Err(e) => {
+                    // Authentication failed
+                    self.socket.write_all(&[5, 1]).await?; // 1 means failure
+                    return Err(e);
+                }
             }
         } else if methods.contains(&(AuthMethod::NoAuthRequired as u8)) {
             // Respond with No Authentication Required
 
-            if self.config.allow_anonymous {
+            if self.authenticator.allows_anonymous() {
                 self.socket
                     .write_all(&[5, AuthMethod::NoAuthRequired as u8])
                     .await?;
+                self.identity = Some(Identity::anonymous());
             } else {
                 // If anonymous access is not allowed, send No Acceptable Methods
                 self.socket
@@ -120,107 +242,147 @@ impl ConnectionHandler {
             ));
         }
 
+        if let Some(identity) = &self.identity {
+            log::debug!("Client {} authenticated as {}", self.address, identity);
+        }
+
         // Read the request
         let version = self.socket.read_u8().await?;
         if version != 5 {
             return Err(crate::errors::ServerError::UnsupportedProtocolVersion);
         }
 
-        let command = Command::try_from(self.socket.read_u8().await?)
-            .map_err(|_| crate::errors::ServerError::InvalidRequestFormat)?;
+        let command = self.socket.read_u8().await?;
+        let Ok(command) = Command::try_from(command) else {
+            return self
+                .reject(crate::errors::ServerError::UnsupportedCmd(command))
+                .await;
+        };
 
-        match command {
-            Command::Connect => {}
-            Command::Bind => {
-                self.socket
-                    .write_all(&[5, 7, 0, AddressType::IPv4.into(), 0, 0, 0, 0])
-                    .await?;
+        let _reserved = self.socket.read_u8().await?; // Reserved byte, should be 0x00
 
-                return Err(crate::errors::ServerError::UnsupportedCmd(
-                    Command::Bind.into(),
-                ));
+        let target = match self.read_target_addr().await {
+            Ok(target) => target,
+            Err(e @ crate::errors::ServerError::AddressTypeNotSupported(_)) => {
+                return self.reject(e).await;
             }
+            Err(e) => return Err(e),
+        };
 
-            Command::UDPAssociate => {
-                self.socket
-                    .write_all(&[5, 7, 0, AddressType::IPv4.into(), 0, 0, 0, 0])
-                    .await?;
-                return Err(crate::errors::ServerError::UnsupportedCmd(
-                    Command::UDPAssociate.into(),
-                ));
-            }
+        match command {
+            Command::Connect => self.handle_connect(target).await,
+            Command::Bind => self.handle_bind(target).await,
+            Command::UDPAssociate => self.handle_udp_associate(target).await,
+        }
+    }
+
+    async fn handle_socks4(&mut self) -> crate::errors::Result<()> {
+        let command = self.socket.read_u8().await?;
+        let port = self.socket.read_u16().await?;
+        let mut ip = [0u8; 4];
+        self.socket.read_exact(&mut ip).await?;
+        let user_id = self.read_null_terminated().await?;
+
+        // SOCKS4a: 0.0.0.x (x != 0) means a domain name follows the USERID
+        let target = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+            let domain = self.read_null_terminated().await?;
+            let domain = String::from_utf8(domain)
+                .map_err(|_| crate::errors::ServerError::InvalidRequestFormat)?;
+            TargetAddr::Domain(domain, port)
+        } else {
+            TargetAddr::Ip(SocketAddr::from((Ipv4Addr::from(ip), port)))
         };
 
-        let _reserved = self.socket.read_u8().await?; // Reserved byte, should be 0x00
+        log::debug!(
+            "SOCKS4 request from {}: command={}, target={}, userid={:?}",
+            self.address,
+            command,
+            target,
+            String::from_utf8_lossy(&user_id)
+        );
+
+        // The whole request is read first so the client gets the reply
+        // instead of a reset
+        if self.config.disable_socks4 {
+            return self
+                .reject(crate::errors::ServerError::UnsupportedProtocolVersion)
+                .await;
+        }
 
-        let address_type = AddressType::try_from(self.socket.read_u8().await?)
-            .map_err(|_| crate::errors::ServerError::InvalidRequestFormat)?;
+        // SOCKS4 has no way to carry a password, and the USERID is not one
+        if !self.authenticator.allows_anonymous() {
+            return self
+                .reject(crate::errors::ServerError::AuthenticationFailed(
+                    "SOCKS4 requires anonymous access to be allowed".to_string(),
+                ))
+                .await;
+        }
+        self.identity = Some(Identity::anonymous());
 
-        let address;
+        match Command::try_from(command) {
+            Ok(Command::Connect) => self.handle_connect(target).await,
+            Ok(Command::Bind) => self.handle_bind(target).await,
+            _ => {
+                self.reject(crate::errors::ServerError::UnsupportedCmd(command))
+                    .await
+            }
+        }
+    }
+
+    /// Reads a NUL-terminated SOCKS4 field (USERID or domain name).
+    async fn read_null_terminated(&mut self) -> crate::errors::Result<Vec<u8>> {
+        let mut field = Vec::new();
+        loop {
+            let byte = self.socket.read_u8().await?;
+            if byte == 0 {
+                return Ok(field);
+            }
+            if field.len() == 255 {
+                return Err(crate::errors::ServerError::InvalidRequestFormat);
+            }
+            field.push(byte);
+        }
+    }
 
-        match address_type {
+    async fn read_target_addr(&mut self) -> crate::errors::Result<TargetAddr> {
+        let address_type = self.socket.read_u8().await?;
+        let address_type = AddressType::try_from(address_type)
+            .map_err(|_| crate::errors::ServerError::AddressTypeNotSupported(address_type))?;
+
+        let target = match address_type {
             AddressType::IPv4 => {
                 let mut ip = [0u8; 4];
                 self.socket.read_exact(&mut ip).await?;
-                address = format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+                let port = self.socket.read_u16().await?;
+                TargetAddr::Ip(SocketAddr::from((Ipv4Addr::from(ip), port)))
             }
             AddressType::DomainName => {
                 let length = self.socket.read_u8().await? as usize;
                 let mut domain = vec![0u8; length];
                 self.socket.read_exact(&mut domain).await?;
-                address = String::from_utf8(domain)
+                let domain = String::from_utf8(domain)
                     .map_err(|_| crate::errors::ServerError::InvalidRequestFormat)?;
+                let port = self.socket.read_u16().await?;
+                TargetAddr::Domain(domain, port)
             }
             AddressType::IPv6 => {
                 let mut ip = [0u8; 16];
                 self.socket.read_exact(&mut ip).await?;
-                address = format!(
-                    "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
-                    u16::from_be_bytes([ip[0], ip[1]]),
-                    u16::from_be_bytes([ip[2], ip[3]]),
-                    u16::from_be_bytes([ip[4], ip[5]]),
-                    u16::from_be_bytes([ip[6], ip[7]]),
-                    u16::from_be_bytes([ip[8], ip[9]]),
-                    u16::from_be_bytes([ip[10], ip[11]]),
-                    u16::from_be_bytes([ip[12], ip[13]]),
-                    u16::from_be_bytes([ip[14], ip[15]])
-                );
+                let port = self.socket.read_u16().await?;
+                TargetAddr::Ip(SocketAddr::from((Ipv6Addr::from(ip), port)))
             }
-        }
+        };
 
-        let port = self.socket.read_u16().await?;
+        Ok(target)
+    }
 
-        let target_address = format!("{}:{}", address, port);
+    async fn handle_connect(&mut self, target: TargetAddr) -> crate::errors::Result<()> {
+        let target_address = target.to_string();
 
         log::info!("Connecting to target address: {}", target_address);
 
         // Resolve and connect via DNS cache for domain names
-        let target_socket_res: Result<tokio::net::TcpStream, std::io::Error> = match address_type {
-            AddressType::DomainName => {
-                let addrs = self
-                    .dns_cache
-                    .resolve(&address, port)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
-
-                // Try each resolved address until one connects
-                let mut last_err: Option<std::io::Error> = None;
-                let mut success: Option<tokio::net::TcpStream> = None;
-                for addr in addrs {
-                    match tokio::net::TcpStream::connect(addr).await {
-                        Ok(s) => {
-                            success = Some(s);
-                            break;
-                        }
-                        Err(e) => {
-                            last_err = Some(e);
-                        }
-                    }
-                }
-                if let Some(s) = success { Ok(s) } else { Err(last_err.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Failed to connect to any resolved address"))) }
-            }
-            _ => tokio::net::TcpStream::connect(&target_address).await,
-        };
+        let target_socket_res = connect_target(&self.dns_cache, &self.dest_filter, &self.connector, &target).await;
 
         match target_socket_res {
             Ok(mut target_socket) => {
@@ -229,10 +391,9 @@ impl ConnectionHandler {
                     target_address
                 );
 
-                // Send the response
-                self.socket
-                    .write_all(&[5, 0, 0, 1u8, 0, 0, 0, 0, 0, 0])
-                    .await?;
+                // Send the response with the address we connect from
+                let bound_address = target_socket.local_addr()?;
+                self.send_reply(Reply::Succeeded, &bound_address).await?;
 
                 log::info!(
                     "Response sent to client for connection to {}",
@@ -260,18 +421,262 @@ impl ConnectionHandler {
                     target_address,
                     e
                 );
-                self.socket
-                    .write_all(&[5, 1, 0, address_type.into(), 0, 0, 0, 0])
-                    .await?;
-                return Err(crate::errors::ServerError::ConnectionError(e.to_string()));
+                return self.reject(e).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_bind(&mut self, target: TargetAddr) -> crate::errors::Result<()> {
+        // Listen on the interface the client reached us through
+        let local_ip = self.socket.local_addr()?.ip();
+        let listener = match tokio::net::TcpListener::bind(SocketAddr::new(local_ip, 0)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                return self
+                    .reject(crate::errors::ServerError::BindError(e.to_string()))
+                    .await;
+            }
+        };
+        let listen_address = listener.local_addr()?;
+
+        log::info!(
+            "BIND for {} listening on {}, expecting {}",
+            self.address,
+            listen_address,
+            target
+        );
+
+        // First reply: where the peer should connect to
+        self.send_reply(Reply::Succeeded, &listen_address).await?;
+
+        let timeout = Duration::from_secs(self.config.bind_timeout_secs);
+        let (mut peer_socket, peer_address) =
+            match tokio::time::timeout(timeout, listener.accept()).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    return self
+                        .reject(crate::errors::ServerError::ConnectionError(e.to_string()))
+                        .await;
+                }
+                Err(_) => {
+                    return self
+                        .reject(crate::errors::ServerError::TtlExpired(format!(
+                            "no inbound connection on {} within {:?}",
+                            listen_address, timeout
+                        )))
+                        .await;
+                }
+            };
+        drop(listener);
+
+        if !self.is_expected_peer(&target, &peer_address).await {
+            log::warn!(
+                "BIND for {}: rejected inbound connection from {}, expected {}",
+                self.address,
+                peer_address,
+                target
+            );
+            return self
+                .reject(crate::errors::ServerError::ConnectionNotAllowed(format!(
+                    "unexpected inbound connection from {}",
+                    peer_address
+                )))
+                .await;
+        }
+
+        // Second reply: who connected
+        self.send_reply(Reply::Succeeded, &peer_address).await?;
+
+        log::info!(
+            "BIND for {}: accepted inbound connection from {}",
+            self.address,
+            peer_address
+        );
+
+        tokio::io::copy_bidirectional(&mut self.socket, &mut peer_socket).await?;
+
+        peer_socket.shutdown().await?;
+
+        log::info!("Closed inbound connection from {}", peer_address);
+
+        self.close().await?;
+
+        Ok(())
+    }
+
+    /// Checks the peer of a BIND against DST.ADDR. An unspecified address
+    /// accepts any peer; the port is not compared because the peer usually
+    /// connects from an ephemeral or protocol-defined port.
+    async fn is_expected_peer(&self, target: &TargetAddr, peer: &SocketAddr) -> bool {
+        let peer_ip = peer.ip().to_canonical();
+        match target {
+            TargetAddr::Ip(addr) => {
+                addr.ip().is_unspecified() || addr.ip().to_canonical() == peer_ip
             }
+            TargetAddr::Domain(domain, port) => match self.dns_cache.resolve(domain, *port).await {
+                Ok(addrs) => addrs.iter().any(|a| a.ip().to_canonical() == peer_ip),
+                Err(e) => {
+                    log::warn!("Failed to resolve BIND address {}: {}", domain, e);
+                    false
+                }
+            },
         }
+    }
+
+    async fn handle_udp_associate(&mut self, target: TargetAddr) -> crate::errors::Result<()> {
+        // Bind the relay on the interface the client reached us through
+        let local_ip = self.socket.local_addr()?.ip();
+        let relay = match UdpSocket::bind(SocketAddr::new(local_ip, 0)).await {
+            Ok(relay) => relay,
+            Err(e) => {
+                return self
+                    .reject(crate::errors::ServerError::BindError(e.to_string()))
+                    .await;
+            }
+        };
+        let relay_address = relay.local_addr()?;
+
+        // Only the client that set up the association may use it. The port is
+        // pinned to DST.PORT when the client announced one, otherwise to the
+        // source port of its first datagram.
+        let client_port = match target {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => port,
+        };
+
+        log::info!(
+            "UDP association for {} relaying on {}",
+            self.address,
+            relay_address
+        );
+
+        self.send_reply(Reply::Succeeded, &relay_address).await?;
+
+        let udp_relay = crate::udp_relay::UdpRelay::new(
+            relay,
+            self.address.ip(),
+            client_port,
+            self.dns_cache.clone(),
+            self.dest_filter.clone(),
+        );
+        udp_relay.run(&mut self.socket).await?;
+
+        log::info!("UDP association for {} closed", self.address);
 
         Ok(())
     }
 
+    async fn send_reply(&mut self, rep: Reply, bound: &SocketAddr) -> crate::errors::Result<()> {
+        let reply = match self.protocol {
+            Protocol::Socks4 => encode_socks4_reply(rep, bound),
+            Protocol::Socks5 => encode_reply(rep, bound),
+        };
+        self.socket.write_all(&reply).await?;
+        Ok(())
+    }
+
+    /// Sends the failure reply matching `err` and returns it.
+    async fn reject(&mut self, err: crate::errors::ServerError) -> crate::errors::Result<()> {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        self.send_reply(err.reply(), &unspecified).await?;
+        Err(err)
+    }
+
     pub async fn close(&mut self) -> crate::errors::Result<()> {
         self.socket.shutdown().await?;
         Ok(())
     }
 }
+
+/// Connects to `target`, resolving domain names through the DNS cache and
+/// racing the resolved addresses with `connector`. Addresses denied by
+/// `dest_filter` are skipped without connecting.
+pub async fn connect_target(
+    dns_cache: &DnsCache,
+    dest_filter: &DestFilter,
+    connector: &Connector,
+    target: &TargetAddr,
+) -> crate::errors::Result<tokio::net::TcpStream> {
+    let (domain, addrs) = match target {
+        TargetAddr::Domain(domain, port) => {
+            // Names the rules refuse are never looked up
+            if dest_filter.denies_domain(domain, *port) {
+                return Err(crate::errors::ServerError::ConnectionNotAllowed(format!(
+                    "{} denied by destination rules",
+                    target
+                )));
+            }
+            (Some(domain.as_str()), dns_cache.resolve(domain, *port).await?)
+        }
+        TargetAddr::Ip(addr) => (None, vec![*addr]),
+    };
+
+    let allowed: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| dest_filter.allows(domain, addr.ip(), addr.port()))
+        .collect();
+    if !allowed.is_empty() {
+        return connector
+            .connect(&allowed)
+            .await
+            .map_err(crate::errors::ServerError::from_connect_error);
+    }
+    Err(if addrs.is_empty() {
+        crate::errors::ServerError::HostUnreachable(format!("no address for {}", target))
+    } else {
+        crate::errors::ServerError::ConnectionNotAllowed(format!("{} denied by destination rules", target))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_carry_ipv4_bound_addresses() {
+        let bound = "192.0.2.1:1080".parse().unwrap();
+        assert_eq!(encode_reply(Reply::Succeeded, &bound), [5, 0, 0, 1, 192, 0, 2, 1, 0x04, 0x38]);
+        // Mapped addresses are sent as plain IPv4
+        let mapped = "[::ffff:192.0.2.1]:1080".parse().unwrap();
+        assert_eq!(encode_reply(Reply::Succeeded, &mapped), [5, 0, 0, 1, 192, 0, 2, 1, 0x04, 0x38]);
+    }
+
+    #[test]
+    fn replies_carry_ipv6_bound_addresses() {
+        let bound = "[2001:db8::1]:443".parse().unwrap();
+        let mut expected = vec![5, 0, 0, 4, 0x20, 0x01, 0x0d, 0xb8];
+        expected.extend_from_slice(&[0; 11]);
+        expected.extend_from_slice(&[1, 0x01, 0xbb]);
+        assert_eq!(encode_reply(Reply::Succeeded, &bound), expected);
+    }
+
+    #[test]
+    fn replies_carry_the_rep_code() {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        for code in 0x00..=0x08 {
+            let rep = Reply::try_from(code).unwrap();
+            assert_eq!(encode_reply(rep, &unspecified), [5, code, 0, 1, 0, 0, 0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn socks4_replies_grant_or_reject() {
+        let bound = "192.0.2.1:1080".parse().unwrap();
+        assert_eq!(encode_socks4_reply(Reply::Succeeded, &bound), [0, 90, 0x04, 0x38, 192, 0, 2, 1]);
+        for code in 0x01..=0x08 {
+            let rep = Reply::try_from(code).unwrap();
+            assert_eq!(encode_socks4_reply(rep, &bound)[1], 91, "{:?}", rep);
+        }
+    }
+
+    #[test]
+    fn socks4_replies_zero_ipv6_addresses() {
+        let bound = "[2001:db8::1]:1080".parse().unwrap();
+        assert_eq!(encode_socks4_reply(Reply::Succeeded, &bound), [0, 90, 0x04, 0x38, 0, 0, 0, 0]);
+        let mapped = "[::ffff:192.0.2.1]:1080".parse().unwrap();
+        assert_eq!(encode_socks4_reply(Reply::Succeeded, &mapped), [0, 90, 0x04, 0x38, 192, 0, 2, 1]);
+    }
+}
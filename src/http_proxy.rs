@@ -0,0 +1,732 @@
+use crate::connector::Connector;
+use crate::dest_filter::DestFilter;
+use crate::dns_cache::DnsCache;
+use crate::errors::{Result, ServerError};
+use crate::handlers::{connect_target, TargetAddr};
+use crate::auth::Authenticator;
+use base64::Engine;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Headers that only apply to a single hop and are never forwarded.
+/// `Transfer-Encoding` is set again by the proxy from the body framing it
+/// actually uses on the next hop.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Returns true if `first_byte` can start an HTTP request line rather than a SOCKS greeting.
+pub fn looks_like_http(first_byte: u8) -> bool {
+    first_byte.is_ascii_uppercase()
+}
+
+/// Parsed request line and headers of an HTTP request.
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Parsed status line and headers of an HTTP response.
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Returns the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl ResponseHead {
+    /// Returns the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// How the end of a message body is determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    /// The body runs until the sender closes the connection.
+    UntilClose,
+}
+
+/// Parses a message head from the start of a buffer, returning it with its length.
+type HeadParser<T> = fn(&[u8]) -> Result<Option<(T, usize)>>;
+
+/// A TCP stream with the bytes read past the last parsed element.
+struct HttpStream {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl HttpStream {
+    fn new(stream: TcpStream) -> Self {
+        HttpStream {
+            stream,
+            buf: Vec::with_capacity(4096),
+        }
+    }
+
+    async fn fill(&mut self) -> Result<usize> {
+        Ok(self.stream.read_buf(&mut self.buf).await?)
+    }
+
+    /// Reads until `parse` recognises a complete head and consumes it.
+    /// Returns `None` if the peer closed the connection before sending anything.
+    async fn read_head<T>(&mut self, parse: HeadParser<T>) -> Result<Option<T>> {
+        loop {
+            if !self.buf.is_empty()
+                && let Some((head, len)) = parse(&self.buf)?
+            {
+                self.buf.drain(..len);
+                return Ok(Some(head));
+            }
+            if self.buf.len() >= MAX_HEAD_SIZE {
+                return Err(ServerError::InvalidRequestFormat);
+            }
+            if self.fill().await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(ServerError::InvalidRequestFormat)
+                };
+            }
+        }
+    }
+
+    /// Reads one CRLF-terminated line, including the terminator.
+    async fn read_line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                return Ok(self.buf.drain(..=pos).collect());
+            }
+            if self.buf.len() >= MAX_HEAD_SIZE || self.fill().await? == 0 {
+                return Err(ServerError::InvalidRequestFormat);
+            }
+        }
+    }
+
+    /// Copies exactly `len` body bytes to `writer`.
+    async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, mut len: u64, writer: &mut W) -> Result<()> {
+        while len > 0 {
+            if self.buf.is_empty() && self.fill().await? == 0 {
+                return Err(ServerError::ConnectionError(
+                    "Connection closed in the middle of a body".to_string(),
+                ));
+            }
+            let n = self.buf.len().min(usize::try_from(len).unwrap_or(usize::MAX));
+            writer.write_all(&self.buf[..n]).await?;
+            self.buf.drain(..n);
+            len -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies a chunked body to `writer`. With `keep_framing` the chunk
+    /// framing and trailers are copied too; otherwise only the data is, for
+    /// clients that do not understand chunked encoding.
+    async fn copy_chunked<W: AsyncWrite + Unpin>(&mut self, writer: &mut W, keep_framing: bool) -> Result<()> {
+        loop {
+            let line = self.read_line().await?;
+            if keep_framing {
+                writer.write_all(&line).await?;
+            }
+            let size = parse_chunk_size(&line)?;
+            if size == 0 {
+                break;
+            }
+            if keep_framing {
+                // Chunk data followed by its CRLF
+                self.copy_exact(size + 2, writer).await?;
+            } else {
+                self.copy_exact(size, writer).await?;
+                if !is_empty_line(&self.read_line().await?) {
+                    return Err(ServerError::InvalidRequestFormat);
+                }
+            }
+        }
+        // Trailer section ends with an empty line
+        loop {
+            let line = self.read_line().await?;
+            if keep_framing {
+                writer.write_all(&line).await?;
+            }
+            if is_empty_line(&line) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Copies everything until the peer closes the connection.
+    async fn copy_to_end<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        tokio::io::copy(&mut self.stream, writer).await?;
+        Ok(())
+    }
+
+    async fn copy_body<W: AsyncWrite + Unpin>(&mut self, length: BodyLength, writer: &mut W) -> Result<()> {
+        match length {
+            BodyLength::Empty => Ok(()),
+            BodyLength::Fixed(len) => self.copy_exact(len, writer).await,
+            BodyLength::Chunked => self.copy_chunked(writer, true).await,
+            BodyLength::UntilClose => self.copy_to_end(writer).await,
+        }
+    }
+}
+
+/// An open connection to an origin server, kept for keep-alive reuse.
+struct Origin {
+    authority: String,
+    stream: HttpStream,
+}
+
+impl Origin {
+    /// True if the connection is still open and has nothing left unread.
+    fn is_idle(&self) -> bool {
+        let mut probe = [0u8; 1];
+        self.stream.buf.is_empty()
+            && matches!(
+                self.stream.stream.try_read(&mut probe),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+            )
+    }
+}
+
+/// Handles HTTP proxy clients accepted on the SOCKS listener: `CONNECT host:port`
+/// tunnels and absolute-URI requests forwarded to the origin server.
+pub struct HttpProxyHandler {
+    client: HttpStream,
+    address: SocketAddr,
+    dns_cache: Arc<DnsCache>,
+    dest_filter: Arc<DestFilter>,
+    connector: Arc<Connector>,
+    authenticator: Arc<dyn Authenticator>,
+    origin: Option<Origin>,
+}
+
+impl HttpProxyHandler {
+    pub fn new(socket: TcpStream, address: SocketAddr, dns_cache: Arc<DnsCache>, dest_filter: Arc<DestFilter>, connector: Arc<Connector>, authenticator: Arc<dyn Authenticator>) -> Self {
+        HttpProxyHandler {
+            client: HttpStream::new(socket),
+            address,
+            dns_cache,
+            dest_filter,
+            connector,
+            authenticator,
+            origin: None,
+        }
+    }
+
+    pub async fn handle(&mut self) -> Result<()> {
+        loop {
+            let head = match self.client.read_head(parse_request_head).await {
+                Ok(Some(head)) => head,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    self.send_response(400, "Bad Request", "").await?;
+                    return Err(e);
+                }
+            };
+
+            if !self.is_authorized(&head).await {
+                self.send_response(
+                    407,
+                    "Proxy Authentication Required",
+                    "Proxy-Authenticate: Basic realm=\"rusk-socks5\"\r\n",
+                )
+                .await?;
+                return Err(ServerError::AuthenticationFailed(
+                    "Missing or invalid Proxy-Authorization".to_string(),
+                ));
+            }
+
+            if head.method.eq_ignore_ascii_case("CONNECT") {
+                return self.handle_connect(&head).await;
+            }
+
+            if !self.handle_forward(&head).await? {
+                return self.close().await;
+            }
+        }
+    }
+
+    async fn handle_connect(&mut self, head: &RequestHead) -> Result<()> {
+        let Some(target) = parse_authority(&head.target, None) else {
+            self.send_response(400, "Bad Request", "").await?;
+            return Err(ServerError::InvalidRequestFormat);
+        };
+
+        log::info!("HTTP CONNECT from {} to {}", self.address, target);
+
+        let mut target_socket = match connect_target(&self.dns_cache, &self.dest_filter, &self.connector, &target).await {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Failed to connect to target address {}: {}", target, e);
+                let (status, reason) = status_for(&e);
+                self.send_response(status, reason, "").await?;
+                return Err(e);
+            }
+        };
+
+        self.client
+            .stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        // Bytes the client sent right after the request head belong to the tunnel
+        if !self.client.buf.is_empty() {
+            target_socket.write_all(&self.client.buf).await?;
+            self.client.buf.clear();
+        }
+
+        tokio::io::copy_bidirectional(&mut self.client.stream, &mut target_socket).await?;
+
+        target_socket.shutdown().await?;
+
+        log::info!("Closed HTTP tunnel to {}", target);
+
+        self.close().await
+    }
+
+    /// Forwards one absolute-URI request and its response.
+    /// Returns whether the client connection can be kept alive.
+    async fn handle_forward(&mut self, head: &RequestHead) -> Result<bool> {
+        let Some((authority, path)) = split_absolute_uri(&head.target) else {
+            self.send_response(400, "Bad Request", "").await?;
+            return Err(ServerError::InvalidRequestFormat);
+        };
+        let Some(target) = parse_authority(authority, Some(80)) else {
+            self.send_response(400, "Bad Request", "").await?;
+            return Err(ServerError::InvalidRequestFormat);
+        };
+        let Ok(request_length) = request_body_length(head) else {
+            self.send_response(400, "Bad Request", "").await?;
+            return Err(ServerError::InvalidRequestFormat);
+        };
+
+        log::info!("HTTP {} from {} to {}", head.method, self.address, head.target);
+
+        if self
+            .origin
+            .as_ref()
+            .is_none_or(|o| o.authority != authority || !o.is_idle())
+        {
+            match connect_target(&self.dns_cache, &self.dest_filter, &self.connector, &target).await {
+                Ok(stream) => {
+                    self.origin = Some(Origin {
+                        authority: authority.to_string(),
+                        stream: HttpStream::new(stream),
+                    });
+                }
+                Err(e) => {
+                    log::error!("Failed to connect to target address {}: {}", target, e);
+                    let (status, reason) = status_for(&e);
+                    self.send_response(status, reason, "").await?;
+                    return Err(e);
+                }
+            }
+        }
+        let Some(origin) = self.origin.as_mut() else {
+            return Ok(false);
+        };
+
+        // We answer Expect: 100-continue ourselves so the body is not held back
+        let expects_continue = head
+            .header("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        if expects_continue {
+            self.client
+                .stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await?;
+        }
+
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", head.method, path, authority);
+        for (name, value) in forwardable_headers(&head.headers) {
+            if name.eq_ignore_ascii_case("Host") || name.eq_ignore_ascii_case("Expect") {
+                continue;
+            }
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if request_length == BodyLength::Chunked {
+            request.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        request.push_str("\r\n");
+        origin.stream.stream.write_all(request.as_bytes()).await?;
+        self.client
+            .copy_body(request_length, &mut origin.stream.stream)
+            .await?;
+
+        // Relay interim 1xx responses until the final one arrives
+        let response = loop {
+            let Some(response) = origin.stream.read_head(parse_response_head).await? else {
+                self.origin = None;
+                self.send_response(502, "Bad Gateway", "").await?;
+                return Err(ServerError::ConnectionError(format!(
+                    "{} closed the connection without a response",
+                    authority
+                )));
+            };
+            if (100..200).contains(&response.status) && response.status != 101 {
+                let interim = format!("HTTP/1.1 {} {}\r\n\r\n", response.status, response.reason);
+                self.client.stream.write_all(interim.as_bytes()).await?;
+                continue;
+            }
+            break response;
+        };
+
+        let response_length = response_body_length(&head.method, &response)?;
+        // HTTP/1.0 clients cannot parse chunked encoding: they get the bare
+        // data, delimited by closing the connection
+        let dechunk = response_length == BodyLength::Chunked && head.version == 0;
+        // Proxy-Connection is a non-standard alias some clients send; it can
+        // only veto keep-alive when present
+        let keep_alive = wants_keep_alive(head.version, head.header("Connection"))
+            && head
+                .header("Proxy-Connection")
+                .is_none_or(|v| wants_keep_alive(head.version, Some(v)))
+            && response_length != BodyLength::UntilClose
+            && !dechunk;
+
+        let mut response_head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+        for (name, value) in forwardable_headers(&response.headers) {
+            response_head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if response_length == BodyLength::Chunked && !dechunk {
+            response_head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        response_head.push_str(if keep_alive {
+            "Connection: keep-alive\r\n\r\n"
+        } else {
+            "Connection: close\r\n\r\n"
+        });
+        self.client.stream.write_all(response_head.as_bytes()).await?;
+        if dechunk {
+            origin.stream.copy_chunked(&mut self.client.stream, false).await?;
+        } else {
+            origin
+                .stream
+                .copy_body(response_length, &mut self.client.stream)
+                .await?;
+        }
+
+        if response_length == BodyLength::UntilClose
+            || !wants_keep_alive(response.version, response.header("Connection"))
+        {
+            self.origin = None;
+        }
+
+        Ok(keep_alive)
+    }
+
+    /// Checks Proxy-Authorization the same way SOCKS5 negotiation does: offered
+    /// credentials are verified when the server has some configured, otherwise
+    /// the request is only let through if anonymous access is allowed.
+    async fn is_authorized(&self, head: &RequestHead) -> bool {
+        match head.header("Proxy-Authorization") {
+            Some(value) if self.authenticator.accepts_credentials() => {
+                match parse_basic_credentials(value) {
+                    Some((username, password)) => {
+                        match self
+                            .authenticator
+                            .authenticate(self.address, &username, &password)
+                            .await
+                        {
+                            Ok(identity) => {
+                                log::debug!("Client {} authenticated as {}", self.address, identity);
+                                true
+                            }
+                            Err(e) => {
+                                log::debug!("Client {} failed authentication: {}", self.address, e);
+                                false
+                            }
+                        }
+                    }
+                    None => false,
+                }
+            }
+            _ => self.authenticator.allows_anonymous(),
+        }
+    }
+
+    async fn send_response(&mut self, status: u16, reason: &str, extra_headers: &str) -> Result<()> {
+        let response = format!(
+            "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, reason, extra_headers
+        );
+        self.client.stream.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    pub async fn close(&mut self) -> Result<()> {
+        self.client.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn collect_headers(headers: &[httparse::Header<'_>]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn parse_request_head(buf: &[u8]) -> Result<Option<(RequestHead, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => Ok(Some((
+            RequestHead {
+                method: request.method.unwrap_or_default().to_string(),
+                target: request.path.unwrap_or_default().to_string(),
+                version: request.version.unwrap_or(1),
+                headers: collect_headers(request.headers),
+            },
+            len,
+        ))),
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(_) => Err(ServerError::InvalidRequestFormat),
+    }
+}
+
+fn parse_response_head(buf: &[u8]) -> Result<Option<(ResponseHead, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => Ok(Some((
+            ResponseHead {
+                version: response.version.unwrap_or(1),
+                status: response.code.unwrap_or_default(),
+                reason: response.reason.unwrap_or_default().to_string(),
+                headers: collect_headers(response.headers),
+            },
+            len,
+        ))),
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(e) => Err(ServerError::ConnectionError(format!(
+            "Invalid response from origin: {}",
+            e
+        ))),
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<u64> {
+    let line = std::str::from_utf8(line).map_err(|_| ServerError::InvalidRequestFormat)?;
+    // Chunk extensions follow a ';'
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| ServerError::InvalidRequestFormat)
+}
+
+fn is_empty_line(line: &[u8]) -> bool {
+    line == b"\r\n" || line == b"\n"
+}
+
+/// The transfer codings listed across all `Transfer-Encoding` headers, or
+/// `None` if there is no such header.
+fn transfer_codings(headers: &[(String, String)]) -> Option<Vec<String>> {
+    let mut values = headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Transfer-Encoding"))
+        .peekable();
+    values.peek()?;
+    Some(
+        values
+            .flat_map(|(_, v)| v.split(','))
+            .map(|c| c.trim().to_ascii_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect(),
+    )
+}
+
+/// Every `Content-Length` value, across repeated headers and comma-separated
+/// lists.
+fn content_lengths(headers: &[(String, String)]) -> Result<Vec<u64>> {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|v| v.trim().parse::<u64>().map_err(|_| ServerError::InvalidRequestFormat))
+        .collect()
+}
+
+/// Frames a request body following RFC 7230 section 3.3.3. Requests the
+/// origin might frame differently than the proxy are refused: a
+/// `Transfer-Encoding` other than exactly `chunked`, one combined with
+/// `Content-Length`, and more than one `Content-Length` value.
+fn request_body_length(head: &RequestHead) -> Result<BodyLength> {
+    let lengths = content_lengths(&head.headers)?;
+    if let Some(codings) = transfer_codings(&head.headers) {
+        return match codings.as_slice() {
+            [coding] if coding == "chunked" && lengths.is_empty() => Ok(BodyLength::Chunked),
+            _ => Err(ServerError::InvalidRequestFormat),
+        };
+    }
+    match lengths.as_slice() {
+        [] => Ok(BodyLength::Empty),
+        [len] => Ok(BodyLength::Fixed(*len)),
+        _ => Err(ServerError::InvalidRequestFormat),
+    }
+}
+
+fn response_body_length(method: &str, response: &ResponseHead) -> Result<BodyLength> {
+    if method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&response.status)
+        || response.status == 204
+        || response.status == 304
+    {
+        return Ok(BodyLength::Empty);
+    }
+    // Transfer-Encoding overrides Content-Length; a body not ending in
+    // chunked runs until the origin closes the connection
+    if let Some(codings) = transfer_codings(&response.headers) {
+        return Ok(match codings.last() {
+            Some(coding) if coding == "chunked" => BodyLength::Chunked,
+            _ => BodyLength::UntilClose,
+        });
+    }
+    // Origins that repeat the same length are tolerated
+    let lengths = content_lengths(&response.headers)?;
+    match lengths.split_first() {
+        None => Ok(BodyLength::UntilClose),
+        Some((len, rest)) if rest.iter().all(|l| l == len) => Ok(BodyLength::Fixed(*len)),
+        Some(_) => Err(ServerError::ConnectionError(
+            "Conflicting Content-Length from origin".to_string(),
+        )),
+    }
+}
+
+/// HTTP/1.1 connections persist unless closed explicitly; HTTP/1.0 ones
+/// only persist when keep-alive is requested.
+fn wants_keep_alive(version: u8, connection: Option<&str>) -> bool {
+    let has_token = |token: &str| {
+        connection.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if version >= 1 {
+        !has_token("close")
+    } else {
+        has_token("keep-alive")
+    }
+}
+
+/// Filters out hop-by-hop headers, including any named in `Connection`, and
+/// `Content-Length` when `Transfer-Encoding` is present, since the latter
+/// decides the framing.
+fn forwardable_headers(headers: &[(String, String)]) -> impl Iterator<Item = &(String, String)> {
+    let connection_tokens: Vec<String> = headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_ascii_lowercase()))
+        .collect();
+    let has_transfer_encoding = find_header(headers, "Transfer-Encoding").is_some();
+    headers.iter().filter(move |(name, _)| {
+        let name = name.to_ascii_lowercase();
+        let framing = has_transfer_encoding && name == "content-length";
+        !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !connection_tokens.contains(&name) && !framing
+    })
+}
+
+/// Splits `http://authority/path?query` into the authority and the origin-form target.
+fn split_absolute_uri(uri: &str) -> Option<(&str, String)> {
+    let scheme_len = "http://".len();
+    if uri.len() < scheme_len || !uri[..scheme_len].eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &uri[scheme_len..];
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    // Drop any userinfo
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let path = rest[end..].split('#').next().unwrap_or_default();
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    Some((authority, path))
+}
+
+/// Parses `host:port` (with brackets around IPv6 literals) into a target.
+/// `default_port` is used when the authority has no port.
+pub fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<TargetAddr> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(TargetAddr::Ip(addr));
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') && !port.contains(']') => {
+            (host, port.parse::<u16>().ok()?)
+        }
+        _ => (authority, default_port?),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Some(TargetAddr::Ip(SocketAddr::new(ip, port))),
+        Err(_) => Some(TargetAddr::Domain(host.to_string(), port)),
+    }
+}
+
+/// Decodes a `Basic` credential into username and password.
+fn parse_basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// HTTP status reported to the client for a failed outbound connection.
+fn status_for(err: &ServerError) -> (u16, &'static str) {
+    match err {
+        ServerError::ConnectionNotAllowed(_) => (403, "Forbidden"),
+        ServerError::TtlExpired(_) => (504, "Gateway Timeout"),
+        _ => (502, "Bad Gateway"),
+    }
+}
@@ -1,78 +1,523 @@
-use ipnet::IpNet;
+use crate::ip_trie::PrefixSet;
+use ipnet::{IpNet, Ipv4Net, Ipv4Subnets, Ipv6Net, Ipv6Subnets};
 use std::net::IpAddr;
 
 #[derive(Debug, Clone)]
 pub enum Rule {
     Cidr(IpNet),
-    WildcardV4 { prefix: String }, // e.g. 192.168.*.* or 10.*
+    /// e.g. 192.168.*.* or 10.*; `None` segments match anything.
+    WildcardV4 { segments: [Option<u8>; 4] },
+    /// e.g. 2001:db8:*:*:*:*:*:1 or 2001:db8:*
+    WildcardV6 { segments: [Option<u16>; 8] },
+    /// Inclusive range such as 10.0.0.5-10.0.0.50; both ends share a family.
+    Range { start: IpAddr, end: IpAddr },
 }
 
+impl Rule {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid IP rule pattern: {}", pattern);
+
+        // Try CIDR first, then a single address
+        if let Ok(net) = pattern.parse::<IpNet>() {
+            return Ok(Rule::Cidr(normalize_net(net)));
+        }
+        if let Ok(ip) = pattern.parse::<IpAddr>() {
+            return Ok(Rule::Cidr(IpNet::from(ip.to_canonical())));
+        }
+
+        if let Some((start, end)) = pattern.split_once('-') {
+            let start = start.trim().parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+            let end = end.trim().parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+            if start.is_ipv4() != end.is_ipv4() || start > end {
+                return Err(invalid());
+            }
+            return Ok(Rule::Range { start, end });
+        }
+
+        // Wildcards: '*' matches any segment, and a trailing '*' also covers
+        // the segments left out, so "10.*" means 10.*.*.*
+        if pattern.contains(':') {
+            parse_wildcard(pattern.split(':'), |s| u16::from_str_radix(s, 16).ok())
+                .map(|segments| Rule::WildcardV6 { segments })
+                .ok_or_else(invalid)
+        } else {
+            parse_wildcard(pattern.split('.'), |s| s.parse::<u8>().ok())
+                .map(|segments| Rule::WildcardV4 { segments })
+                .ok_or_else(invalid)
+        }
+    }
+
+    /// `ip` must already be canonical, see [`IpFilter::allows`].
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (Rule::Cidr(net), ip) => net.contains(ip),
+            (Rule::WildcardV4 { segments }, IpAddr::V4(v4)) => segments_match(segments, &v4.octets()),
+            (Rule::WildcardV6 { segments }, IpAddr::V6(v6)) => segments_match(segments, &v6.segments()),
+            (Rule::Range { start, end }, ip) => start <= ip && ip <= end,
+            _ => false,
+        }
+    }
+}
+
+/// Parses wildcard segments into exactly `N` entries, `None` for '*'.
+fn parse_wildcard<'a, T: Copy, const N: usize>(
+    parts: impl Iterator<Item = &'a str>,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<[Option<T>; N]> {
+    let parts: Vec<&str> = parts.collect();
+    if parts.len() > N || !parts.contains(&"*") {
+        return None;
+    }
+    // Short patterns are only allowed when they end in '*'
+    if parts.len() < N && parts.last() != Some(&"*") {
+        return None;
+    }
+    let mut segments = [None; N];
+    for (segment, part) in segments.iter_mut().zip(&parts) {
+        if *part != "*" {
+            *segment = Some(parse(part)?);
+        }
+    }
+    Some(segments)
+}
+
+fn segments_match<T: PartialEq>(pattern: &[Option<T>], segments: &[T]) -> bool {
+    pattern
+        .iter()
+        .zip(segments)
+        .all(|(p, s)| p.as_ref().is_none_or(|p| p == s))
+}
+
+/// Rewrites IPv4-mapped IPv6 networks (::ffff:0:0/96 and narrower) as IPv4,
+/// matching how client addresses are canonicalized.
+fn normalize_net(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.network().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96)
+                .map(IpNet::V4)
+                .unwrap_or(net),
+            None => net,
+        },
+        _ => net,
+    }
+}
+
+/// Compiled rules: networks, ranges and prefix-shaped wildcards go into a
+/// prefix trie so lookups do not depend on the number of rules. Wildcards
+/// with a fixed segment after a '*' (e.g. 192.168.*.5) are checked one by one.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    prefixes: PrefixSet,
+    wildcards: Vec<Rule>,
+    len: usize,
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, rule: Rule) {
+        self.len += 1;
+        match rule {
+            Rule::Cidr(net) => self.prefixes.insert(net),
+            Rule::Range {
+                start: IpAddr::V4(start),
+                end: IpAddr::V4(end),
+            } => Ipv4Subnets::new(start, end, 0).for_each(|net| self.prefixes.insert(net.into())),
+            Rule::Range {
+                start: IpAddr::V6(start),
+                end: IpAddr::V6(end),
+            } => Ipv6Subnets::new(start, end, 0).for_each(|net| self.prefixes.insert(net.into())),
+            Rule::WildcardV4 { segments } => match wildcard_prefix(&segments) {
+                Some(len) => {
+                    let octets = segments.map(|s| s.unwrap_or(0));
+                    self.prefixes.insert(Ipv4Net::new(octets.into(), len * 8).unwrap().into());
+                }
+                None => self.wildcards.push(rule),
+            },
+            Rule::WildcardV6 { segments } => match wildcard_prefix(&segments) {
+                Some(len) => {
+                    let segments = segments.map(|s| s.unwrap_or(0));
+                    self.prefixes.insert(Ipv6Net::new(segments.into(), len * 16).unwrap().into());
+                }
+                None => self.wildcards.push(rule),
+            },
+            // Mixed-family ranges are rejected by Rule::parse
+            Rule::Range { .. } => self.wildcards.push(rule),
+        }
+    }
+
+    /// `ip` must already be canonical, see [`IpFilter::allows`].
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.prefixes.contains(ip) || self.wildcards.iter().any(|r| r.matches(ip))
+    }
+
+    /// Number of rules inserted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Number of fixed leading segments if all '*' segments come after them.
+fn wildcard_prefix<T>(segments: &[Option<T>]) -> Option<u8> {
+    let fixed = segments.iter().take_while(|s| s.is_some()).count();
+    segments[fixed..]
+        .iter()
+        .all(|s| s.is_none())
+        .then_some(fixed as u8)
+}
+
+/// Source address filter with a whitelist and a blacklist.
+///
+/// A blacklisted address is always rejected, even if it is also
+/// whitelisted, so "allow 10.0.0.0/8 except 10.13.0.0/16" is written as a
+/// whitelist rule plus a blacklist rule. An empty whitelist allows every
+/// address that is not blacklisted.
 #[derive(Debug, Clone, Default)]
 pub struct IpFilter {
-    rules: Vec<Rule>,
+    allow: RuleSet,
+    deny: RuleSet,
 }
 
 impl IpFilter {
     pub fn new() -> Self {
-        Self { rules: Vec::new() }
+        Self {
+            allow: RuleSet::new(),
+            deny: RuleSet::new(),
+        }
     }
 
     pub fn from_strings(patterns: &[String]) -> Result<Self, String> {
+        Self::from_lists(patterns, &[])
+    }
+
+    pub fn from_lists(allow: &[String], deny: &[String]) -> Result<Self, String> {
         let mut filter = Self::new();
-        for p in patterns {
+        for p in allow {
             filter.add_rule(p)?;
         }
+        for p in deny {
+            filter.add_deny_rule(p)?;
+        }
         Ok(filter)
     }
 
+    /// Adds a whitelist rule.
     pub fn add_rule(&mut self, pattern: &str) -> Result<(), String> {
-        // Try CIDR first
-        if let Ok(net) = pattern.parse::<IpNet>() {
-            self.rules.push(Rule::Cidr(net));
-            return Ok(());
+        self.allow.insert(Rule::parse(pattern)?);
+        Ok(())
+    }
+
+    /// Adds a blacklist rule.
+    pub fn add_deny_rule(&mut self, pattern: &str) -> Result<(), String> {
+        self.deny.insert(Rule::parse(pattern)?);
+        Ok(())
+    }
+
+    /// Adds whitelist rules from a file, one per line. Blank lines and
+    /// `#` comments are ignored.
+    pub fn add_rules_file(&mut self, path: &str) -> Result<(), String> {
+        for (line, pattern) in Self::read_rules_file(path)? {
+            self.add_rule(&pattern)
+                .map_err(|e| format!("{}: line {}: {}", path, line, e))?;
         }
+        Ok(())
+    }
 
-        // Simple wildcard support for IPv4: segments separated by '.', '*' matches any segment.
-        if Self::is_ipv4_wildcard(pattern) {
-            let prefix = pattern.to_string();
-            self.rules.push(Rule::WildcardV4 { prefix });
-            return Ok(());
+    /// Adds blacklist rules from a file, in the same format as [`IpFilter::add_rules_file`].
+    pub fn add_deny_rules_file(&mut self, path: &str) -> Result<(), String> {
+        for (line, pattern) in Self::read_rules_file(path)? {
+            self.add_deny_rule(&pattern)
+                .map_err(|e| format!("{}: line {}: {}", path, line, e))?;
         }
+        Ok(())
+    }
+
+    /// Returns the rules in a file with their line numbers.
+    fn read_rules_file(path: &str) -> Result<Vec<(usize, String)>, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read IP rules file {}: {}", path, e))?;
+        Ok(contents
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or_default().trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| (number, line.to_string()))
+            .collect())
+    }
+
+    pub fn whitelist_len(&self) -> usize {
+        self.allow.len()
+    }
 
-        Err(format!("Invalid IP rule pattern: {}", pattern))
+    pub fn blacklist_len(&self) -> usize {
+        self.deny.len()
     }
 
+    /// IPv4-mapped IPv6 addresses, as seen on dual-stack listeners, are
+    /// matched as the IPv4 address they carry.
     pub fn allows(&self, ip: &IpAddr) -> bool {
-        if self.rules.is_empty() {
+        let ip = &ip.to_canonical();
+        if self.deny.contains(ip) {
+            return false;
+        }
+        if self.allow.is_empty() {
             // No whitelist configured -> allow all
             return true;
         }
-        self.rules.iter().any(|r| match (r, ip) {
-            (Rule::Cidr(net), ip) => net.contains(ip),
-            (Rule::WildcardV4 { prefix }, IpAddr::V4(v4)) => {
-                let ip_str = v4.to_string();
-                Self::ipv4_wildcard_match(prefix, &ip_str)
-            }
-            _ => false,
-        })
+        self.allow.contains(ip)
     }
+}
 
-    fn is_ipv4_wildcard(p: &str) -> bool {
-        // Accept patterns like "192.168.*.*", "10.*", "*.*.*.*"
-        p.split('.').all(|s| s == "*" || s.parse::<u8>().is_ok())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_set(patterns: &[&str]) -> RuleSet {
+        let mut set = RuleSet::new();
+        for pattern in patterns {
+            set.insert(Rule::parse(pattern).unwrap());
+        }
+        set
     }
 
-    fn ipv4_wildcard_match(pattern: &str, ip: &str) -> bool {
-        let p_parts: Vec<&str> = pattern.split('.').collect();
-        let ip_parts: Vec<&str> = ip.split('.').collect();
-        if p_parts.len() != ip_parts.len() {
-            return false;
+    fn contains(set: &RuleSet, ip: &str) -> bool {
+        set.contains(&ip.parse().unwrap())
+    }
+
+    fn lists(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        IpFilter::from_lists(&strings(allow), &strings(deny)).unwrap()
+    }
+
+    fn allows(filter: &IpFilter, ip: &str) -> bool {
+        filter.allows(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn blacklist_wins_over_whitelist() {
+        let filter = lists(&["10.0.0.0/8", "192.0.2.7"], &["10.13.0.0/16", "192.0.2.7"]);
+        assert!(allows(&filter, "10.1.2.3"));
+        assert!(!allows(&filter, "10.13.2.3"));
+        assert!(!allows(&filter, "192.0.2.7"));
+        assert!(!allows(&filter, "198.51.100.1"));
+    }
+
+    #[test]
+    fn blacklist_alone_allows_everything_else() {
+        let filter = lists(&[], &["10.13.0.0/16", "::ffff:192.0.2.7"]);
+        assert!(allows(&filter, "10.1.2.3"));
+        assert!(allows(&filter, "2001:db8::1"));
+        assert!(!allows(&filter, "10.13.0.1"));
+        assert!(!allows(&filter, "192.0.2.7"));
+        assert!(!allows(&filter, "::ffff:192.0.2.7"));
+        assert!(allows(&IpFilter::new(), "192.0.2.7"));
+    }
+
+    #[test]
+    fn short_wildcards_cover_the_left_out_segments() {
+        // Until IPv6 wildcards were added, "10.*" matched no address at all
+        let filter = lists(&["10.*"], &[]);
+        assert!(allows(&filter, "10.0.0.0"));
+        assert!(allows(&filter, "10.255.255.255"));
+        assert!(!allows(&filter, "11.0.0.0"));
+        assert!(!allows(&filter, "9.255.255.255"));
+
+        let filter = lists(&["192.168.*"], &[]);
+        assert!(allows(&filter, "192.168.7.9"));
+        assert!(!allows(&filter, "192.169.0.1"));
+    }
+
+    #[test]
+    fn wildcards_with_fixed_trailing_segments() {
+        let filter = lists(&["192.168.*.5", "*.*.*.1"], &[]);
+        assert!(allows(&filter, "192.168.77.5"));
+        assert!(!allows(&filter, "192.168.77.6"));
+        assert!(allows(&filter, "203.0.113.1"));
+        assert!(!allows(&filter, "203.0.113.2"));
+    }
+
+    #[test]
+    fn ipv6_wildcards() {
+        let filter = lists(&["2001:db8:*"], &[]);
+        assert!(allows(&filter, "2001:db8::1"));
+        assert!(allows(&filter, "2001:db8:ffff:1:2:3:4:5"));
+        assert!(!allows(&filter, "2001:db9::1"));
+        assert!(!allows(&filter, "32.1.13.184"));
+
+        let filter = lists(&["2001:db8:*:*:*:*:*:1"], &[]);
+        assert!(allows(&filter, "2001:db8:a:b:c:d:e:1"));
+        assert!(!allows(&filter, "2001:db8:a:b:c:d:e:2"));
+
+        // IPv4 wildcards never match IPv6 clients and the other way round
+        let filter = lists(&["*.*.*.*"], &[]);
+        assert!(allows(&filter, "198.51.100.1"));
+        assert!(!allows(&filter, "2001:db8::1"));
+        let filter = lists(&["*:*:*:*:*:*:*:*"], &[]);
+        assert!(!allows(&filter, "198.51.100.1"));
+    }
+
+    #[test]
+    fn ranges() {
+        let filter = lists(&["192.0.2.10 - 192.0.2.20", "2001:db8::a-2001:db8::14"], &[]);
+        assert!(!allows(&filter, "192.0.2.9"));
+        assert!(allows(&filter, "192.0.2.10"));
+        assert!(allows(&filter, "192.0.2.20"));
+        assert!(!allows(&filter, "192.0.2.21"));
+        assert!(allows(&filter, "2001:db8::14"));
+        assert!(!allows(&filter, "2001:db8::15"));
+        // A single-address range
+        assert!(allows(&lists(&["192.0.2.1-192.0.2.1"], &[]), "192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_rules_and_clients_are_normalized() {
+        let filter = lists(&["::ffff:10.0.0.0/104", "::ffff:192.0.2.7", "::ffff:198.51.100.1-::ffff:198.51.100.9"], &[]);
+        for ip in ["10.1.2.3", "::ffff:10.1.2.3", "192.0.2.7", "198.51.100.5", "::ffff:198.51.100.9"] {
+            assert!(allows(&filter, ip), "{}", ip);
         }
-        for (p, v) in p_parts.iter().zip(ip_parts.iter()) {
-            if *p != "*" && *p != *v {
-                return false;
+        assert!(!allows(&filter, "11.0.0.1"));
+        assert!(!allows(&filter, "192.0.2.8"));
+
+        // Networks wider than the mapped range stay IPv6
+        let filter = lists(&["::ffff:0:0/95"], &[]);
+        assert!(!allows(&filter, "10.1.2.3"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for pattern in [
+            "",
+            "abc",
+            "10.0.0",
+            "10.*.1",
+            "300.*",
+            "10.0.0.0/33",
+            "*.*.*.*.*",
+            "10.0.0.1.*",
+            "2001:db8:*:g",
+            "2001:db8",
+            "1:2:3:4:5:6:7:8:*",
+            "10.0.0.1-",
+            "10.0.0.5-10.0.0.1",
+            "10.0.0.1-2001:db8::1",
+            "2001:db8::1/129",
+        ] {
+            assert!(Rule::parse(pattern).is_err(), "{:?} was accepted", pattern);
+        }
+        let err = IpFilter::from_lists(&["10.0.0.0/8".to_string()], &["nope".to_string()]).unwrap_err();
+        assert_eq!(err, "Invalid IP rule pattern: nope");
+    }
+
+    #[test]
+    fn ranges_split_at_odd_boundaries() {
+        let set = rule_set(&["10.0.0.5-10.0.0.50"]);
+        assert!(!contains(&set, "10.0.0.4"));
+        for last in 5..=50 {
+            assert!(contains(&set, &format!("10.0.0.{}", last)), "10.0.0.{}", last);
+        }
+        assert!(!contains(&set, "10.0.0.51"));
+        assert!(!contains(&set, "10.0.0.255"));
+
+        let set = rule_set(&["2001:db8::3-2001:db8::1:2"]);
+        assert!(!contains(&set, "2001:db8::2"));
+        assert!(contains(&set, "2001:db8::3"));
+        assert!(contains(&set, "2001:db8::ffff"));
+        assert!(contains(&set, "2001:db8::1:0"));
+        assert!(contains(&set, "2001:db8::1:2"));
+        assert!(!contains(&set, "2001:db8::1:3"));
+    }
+
+    #[test]
+    fn mapped_clients_match_ipv4_rules() {
+        let mut filter = IpFilter::new();
+        filter.add_rule("10.0.0.0/8").unwrap();
+        filter.add_rule("::ffff:192.0.2.0/120").unwrap();
+        for ip in ["10.1.2.3", "::ffff:10.1.2.3", "192.0.2.9", "::ffff:192.0.2.9"] {
+            assert!(filter.allows(&ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(!filter.allows(&"::ffff:11.0.0.1".parse().unwrap()));
+        // Not a mapped address, so not 10.1.2.3
+        assert!(!filter.allows(&"::a01:203".parse().unwrap()));
+    }
+
+    /// xorshift64, so failures can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// An IPv4 address in a few /16s, so rules overlap often.
+        fn ipv4(&mut self) -> std::net::Ipv4Addr {
+            let high = [0x0a00_0000u32, 0xc0a8_0000, 0xc000_0200][(self.next() % 3) as usize];
+            (high | (self.next() as u32 & 0xffff)).into()
+        }
+
+        fn ipv6(&mut self) -> std::net::Ipv6Addr {
+            (0x2001_0db8_u128 << 96 | u128::from(self.next() as u16) << 16 | u128::from(self.next() as u16)).into()
+        }
+    }
+
+    fn random_rule(rng: &mut Rng) -> Rule {
+        match rng.next() % 6 {
+            0 | 1 => {
+                let net = Ipv4Net::new(rng.ipv4(), 8 + (rng.next() % 25) as u8).unwrap();
+                Rule::Cidr(IpNet::V4(net.trunc()))
+            }
+            2 => {
+                let net = Ipv6Net::new(rng.ipv6(), 96 + (rng.next() % 33) as u8).unwrap();
+                Rule::Cidr(IpNet::V6(net.trunc()))
+            }
+            3 => {
+                let (a, b) = (rng.ipv4(), rng.ipv4());
+                Rule::Range {
+                    start: a.min(b).into(),
+                    end: a.max(b).into(),
+                }
+            }
+            4 => {
+                let (a, b) = (rng.ipv6(), rng.ipv6());
+                Rule::Range {
+                    start: a.min(b).into(),
+                    end: a.max(b).into(),
+                }
+            }
+            _ => {
+                let mut segments = rng.ipv4().octets().map(Some);
+                segments[(rng.next() % 4) as usize] = None;
+                Rule::WildcardV4 { segments }
+            }
+        }
+    }
+
+    #[test]
+    fn trie_agrees_with_a_linear_scan() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..50 {
+            let rules: Vec<Rule> = (0..1 + rng.next() % 40).map(|_| random_rule(&mut rng)).collect();
+            let mut set = RuleSet::new();
+            for rule in &rules {
+                set.insert(rule.clone());
+            }
+            for _ in 0..2000 {
+                let ip: IpAddr = if rng.next().is_multiple_of(4) {
+                    rng.ipv6().into()
+                } else {
+                    rng.ipv4().into()
+                };
+                let expected = rules.iter().any(|r| r.matches(&ip));
+                assert_eq!(set.contains(&ip), expected, "{} with rules {:?}", ip, rules);
             }
         }
-        true
     }
 }
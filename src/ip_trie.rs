@@ -0,0 +1,194 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+const ROOT: u32 = 0;
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+struct Node {
+    children: [u32; 2],
+    /// A prefix ends here, so every address below this node matches.
+    terminal: bool,
+}
+
+impl Node {
+    fn new() -> Self {
+        Node {
+            children: [NONE, NONE],
+            terminal: false,
+        }
+    }
+}
+
+/// Binary trie over address bits for one address family. Lookups walk at
+/// most `BITS` nodes regardless of how many prefixes are stored.
+#[derive(Debug, Clone)]
+struct Trie<const BITS: u32> {
+    nodes: Vec<Node>,
+}
+
+impl<const BITS: u32> Trie<BITS> {
+    fn new() -> Self {
+        Trie {
+            nodes: vec![Node::new()],
+        }
+    }
+
+    /// `bits` holds the address left-aligned in the low `BITS` bits.
+    fn insert(&mut self, bits: u128, prefix_len: u8) {
+        let mut node = ROOT;
+        for depth in 0..u32::from(prefix_len) {
+            if self.nodes[node as usize].terminal {
+                // Already covered by a shorter prefix
+                return;
+            }
+            let bit = ((bits >> (BITS - 1 - depth)) & 1) as usize;
+            let mut next = self.nodes[node as usize].children[bit];
+            if next == NONE {
+                next = self.nodes.len() as u32;
+                self.nodes.push(Node::new());
+                self.nodes[node as usize].children[bit] = next;
+            }
+            node = next;
+        }
+        let node = &mut self.nodes[node as usize];
+        node.terminal = true;
+        // Longer prefixes below are now redundant
+        node.children = [NONE, NONE];
+    }
+
+    fn contains(&self, bits: u128) -> bool {
+        let mut node = ROOT;
+        for depth in 0..BITS {
+            let current = &self.nodes[node as usize];
+            if current.terminal {
+                return true;
+            }
+            let bit = ((bits >> (BITS - 1 - depth)) & 1) as usize;
+            node = current.children[bit];
+            if node == NONE {
+                return false;
+            }
+        }
+        self.nodes[node as usize].terminal
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nodes.len() == 1 && !self.nodes[0].terminal
+    }
+}
+
+/// Set of IPv4 and IPv6 networks with prefix-length-bounded membership tests.
+#[derive(Debug, Clone)]
+pub struct PrefixSet {
+    v4: Trie<32>,
+    v6: Trie<128>,
+}
+
+impl Default for PrefixSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrefixSet {
+    pub fn new() -> Self {
+        PrefixSet {
+            v4: Trie::new(),
+            v6: Trie::new(),
+        }
+    }
+
+    pub fn insert(&mut self, net: IpNet) {
+        match net {
+            IpNet::V4(net) => self.v4.insert(u32::from(net.network()).into(), net.prefix_len()),
+            IpNet::V6(net) => self.v6.insert(u128::from(net.network()), net.prefix_len()),
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v4) => self.v4.contains(u32::from(*v4).into()),
+            IpAddr::V6(v6) => self.v6.contains(u128::from(*v6)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes(nets: &[&str]) -> PrefixSet {
+        let mut set = PrefixSet::new();
+        for net in nets {
+            set.insert(net.parse().unwrap());
+        }
+        set
+    }
+
+    fn contains(set: &PrefixSet, ip: &str) -> bool {
+        set.contains(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn nested_prefixes_in_either_order() {
+        for nets in [["10.0.0.0/8", "10.1.0.0/16"], ["10.1.0.0/16", "10.0.0.0/8"]] {
+            let set = prefixes(&nets);
+            assert!(contains(&set, "10.1.2.3"), "{:?}", nets);
+            assert!(contains(&set, "10.200.0.1"), "{:?}", nets);
+            assert!(!contains(&set, "11.0.0.0"), "{:?}", nets);
+        }
+    }
+
+    #[test]
+    fn overlapping_siblings() {
+        let set = prefixes(&["192.168.0.0/24", "192.168.1.0/24", "192.168.0.128/25"]);
+        assert!(contains(&set, "192.168.0.1"));
+        assert!(contains(&set, "192.168.0.200"));
+        assert!(contains(&set, "192.168.1.255"));
+        assert!(!contains(&set, "192.168.2.0"));
+        assert!(!contains(&set, "192.167.255.255"));
+    }
+
+    #[test]
+    fn whole_space_and_single_hosts() {
+        let set = prefixes(&["0.0.0.0/0"]);
+        assert!(contains(&set, "0.0.0.0"));
+        assert!(contains(&set, "255.255.255.255"));
+        assert!(!contains(&set, "::1"));
+
+        let set = prefixes(&["::/0"]);
+        assert!(contains(&set, "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert!(!contains(&set, "1.2.3.4"));
+
+        let set = prefixes(&["192.0.2.7/32", "2001:db8::7/128"]);
+        assert!(contains(&set, "192.0.2.7"));
+        assert!(!contains(&set, "192.0.2.6"));
+        assert!(!contains(&set, "192.0.2.8"));
+        assert!(contains(&set, "2001:db8::7"));
+        assert!(!contains(&set, "2001:db8::6"));
+        assert!(!contains(&set, "2001:db8::8"));
+    }
+
+    #[test]
+    fn families_are_kept_apart() {
+        // Same leading bits in both families
+        let set = prefixes(&["10.0.0.0/8"]);
+        assert!(!contains(&set, "a00::1"));
+        let set = prefixes(&["a00::/8"]);
+        assert!(!contains(&set, "10.0.0.1"));
+        assert!(contains(&set, "a00::1"));
+    }
+
+    #[test]
+    fn empty_until_something_is_inserted() {
+        assert!(PrefixSet::new().is_empty());
+        assert!(!contains(&PrefixSet::new(), "0.0.0.0"));
+        assert!(!prefixes(&["0.0.0.0/0"]).is_empty());
+        assert!(!prefixes(&["2001:db8::/32"]).is_empty());
+    }
+}
@@ -1,6 +1,23 @@
 pub mod server;
 pub mod errors;
 pub mod handlers;
+pub mod connector;
 pub mod cli;
 pub mod dns_cache;
-pub mod ip_filter;
\ No newline at end of file
+pub mod dns_message;
+pub mod resolver;
+pub mod resolver_doh;
+pub mod resolver_dot;
+pub mod ip_filter;
+pub mod ip_trie;
+pub mod rule_reload;
+pub mod dest_filter;
+pub mod geoip;
+pub mod udp_relay;
+pub mod http_proxy;
+pub mod users;
+pub mod auth;
+pub mod auth_webhook;
+pub mod auth_ldap;
+pub mod auth_radius;
+pub mod lockout;
\ No newline at end of file
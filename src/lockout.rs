@@ -0,0 +1,428 @@
+use crate::auth::{Authenticator, Identity};
+use crate::errors::{Result, ServerError};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Most IPs or usernames tracked in one table; beyond that the entry closest
+/// to expiring makes room.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Settings for [`Lockout`].
+#[derive(Debug, Clone)]
+pub struct LockoutConfig {
+    /// Failed logins from one IP, or for one username with `ban_usernames`,
+    /// before it is banned.
+    pub max_failures: u32,
+    /// How long a ban lasts.
+    pub ban_duration: Duration,
+    /// Delay after the first failure; doubled for every further failure.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Failure counts are forgotten after this long without a new failure.
+    pub window: Duration,
+    /// Also ban usernames, not just source IPs. Anyone can then lock an
+    /// account out by sending it `max_failures` wrong passwords.
+    pub ban_usernames: bool,
+}
+
+/// Tracks failed logins per source IP and per username.
+///
+/// Every failure is answered after a delay that grows exponentially with the
+/// larger of the two counts. Once the IP count reaches `max_failures` the IP
+/// is banned for `ban_duration` and dropped at accept time. Usernames are only
+/// banned, and then rejected without asking the backend, if `ban_usernames`
+/// is set.
+pub struct Lockout {
+    config: LockoutConfig,
+    ip_failures: FailureCounts<IpAddr>,
+    user_failures: FailureCounts<String>,
+    banned_ips: BanList<IpAddr>,
+    banned_users: BanList<String>,
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Self {
+        Lockout {
+            ip_failures: FailureCounts::new(config.window),
+            user_failures: FailureCounts::new(config.window),
+            banned_ips: BanList::new(config.ban_duration, "IP"),
+            banned_users: BanList::new(config.ban_duration, "user"),
+            config,
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned_ips.contains(&ip.to_canonical())
+    }
+
+    pub fn is_user_banned(&self, username: &str) -> bool {
+        self.banned_users.contains(username)
+    }
+
+    /// Counts a failed login and returns how long to hold back the answer.
+    pub async fn record_failure(&self, ip: IpAddr, username: &str) -> Duration {
+        let ip = ip.to_canonical();
+        let ip_count = self.ip_failures.increment(ip);
+        let user_count = self.user_failures.increment(username.to_string());
+
+        if ip_count >= self.config.max_failures {
+            log::warn!(
+                "Banning {} for {:?} after {} failed logins",
+                ip,
+                self.config.ban_duration,
+                ip_count
+            );
+            self.ip_failures.clear(&ip);
+            self.banned_ips.ban(ip);
+        }
+        if self.config.ban_usernames && user_count >= self.config.max_failures {
+            log::warn!(
+                "Banning user {} for {:?} after {} failed logins",
+                username,
+                self.config.ban_duration,
+                user_count
+            );
+            self.user_failures.clear(username);
+            self.banned_users.ban(username.to_string());
+        }
+
+        self.delay_for(ip_count.max(user_count))
+    }
+
+    /// Clears the failure count of a username after a successful login. The
+    /// count of the source IP is kept so that one valid account cannot be
+    /// used to keep guessing others.
+    pub async fn record_success(&self, username: &str) {
+        self.user_failures.clear(username);
+    }
+
+    fn delay_for(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.config
+            .base_delay
+            .saturating_mul(factor)
+            .min(self.config.max_delay)
+    }
+}
+
+/// Failure counts that are forgotten `window` after the last failure.
+struct FailureCounts<K> {
+    window: Duration,
+    /// Count and time of the last failure.
+    entries: Mutex<HashMap<K, (u32, Instant)>>,
+}
+
+impl<K: Hash + Eq + Clone> FailureCounts<K> {
+    fn new(window: Duration) -> Self {
+        FailureCounts {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn increment(&self, key: K) -> u32 {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) {
+            make_room(&mut entries, |(_, last)| *last + self.window > now, |(_, last)| *last);
+        }
+        let entry = entries.entry(key).or_insert((0, now));
+        if entry.1 + self.window <= now {
+            entry.0 = 0;
+        }
+        *entry = (entry.0.saturating_add(1), now);
+        entry.0
+    }
+
+    fn clear<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// Keys banned until a deadline. The end of a ban is logged when the key is
+/// next checked.
+struct BanList<K> {
+    ban_duration: Duration,
+    kind: &'static str,
+    entries: Mutex<HashMap<K, Instant>>,
+}
+
+impl<K: Hash + Eq + Clone + Display> BanList<K> {
+    fn new(ban_duration: Duration, kind: &'static str) -> Self {
+        BanList {
+            ban_duration,
+            kind,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ban(&self, key: K) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) {
+            make_room(&mut entries, |until| *until > now, |until| *until);
+        }
+        entries.insert(key, now + self.ban_duration);
+    }
+
+    fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                if let Some((key, _)) = entries.remove_entry(key) {
+                    log::info!("Ban on {} {} lifted", self.kind, key);
+                }
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Drops expired entries once a table is full, and if that is not enough the
+/// entry with the oldest `age`.
+fn make_room<K, V>(entries: &mut HashMap<K, V>, live: impl Fn(&V) -> bool, age: impl Fn(&V) -> Instant)
+where
+    K: Hash + Eq + Clone,
+{
+    if entries.len() < MAX_ENTRIES {
+        return;
+    }
+    entries.retain(|_, v| live(v));
+    if entries.len() >= MAX_ENTRIES
+        && let Some(oldest) = entries.iter().min_by_key(|(_, v)| age(v)).map(|(k, _)| k.clone())
+    {
+        entries.remove(&oldest);
+    }
+}
+
+/// Applies a [`Lockout`] to another authenticator.
+pub struct LockoutAuthenticator {
+    inner: Arc<dyn Authenticator>,
+    lockout: Arc<Lockout>,
+}
+
+impl LockoutAuthenticator {
+    pub fn new(inner: Arc<dyn Authenticator>, lockout: Arc<Lockout>) -> Self {
+        LockoutAuthenticator { inner, lockout }
+    }
+}
+
+#[async_trait]
+impl Authenticator for LockoutAuthenticator {
+    fn accepts_credentials(&self) -> bool {
+        self.inner.accepts_credentials()
+    }
+
+    fn allows_anonymous(&self) -> bool {
+        self.inner.allows_anonymous()
+    }
+
+    async fn authenticate(&self, client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+        // Connections opened before the ban can still try logins, e.g. HTTP keep-alive
+        if self.lockout.is_banned(&client.ip()) || self.lockout.is_user_banned(username) {
+            return Err(ServerError::AuthenticationFailed(
+                "Too many failed logins".to_string(),
+            ));
+        }
+
+        match self.inner.authenticate(client, username, password).await {
+            Ok(identity) => {
+                self.lockout.record_success(username).await;
+                Ok(identity)
+            }
+            // Only rejected credentials count; a backend outage must not
+            // get every client banned
+            Err(e @ ServerError::AuthenticationFailed(_)) => {
+                let delay = self.lockout.record_failure(client.ip(), username).await;
+                log::debug!("Delaying failed login from {} by {:?}", client, delay);
+                tokio::time::sleep(delay).await;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LockoutConfig {
+        LockoutConfig {
+            max_failures: 4,
+            ban_duration: Duration::from_secs(600),
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_millis(1000),
+            window: Duration::from_secs(900),
+            ban_usernames: false,
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    /// Accepts "right" as every user's password, or fails as if the backend
+    /// were down.
+    struct Backend {
+        down: bool,
+    }
+
+    #[async_trait]
+    impl Authenticator for Backend {
+        async fn authenticate(&self, _client: SocketAddr, username: &str, password: &str) -> Result<Identity> {
+            if self.down {
+                Err(ServerError::AuthBackendUnavailable("down".to_string()))
+            } else if password == "right" {
+                Ok(Identity::user(username))
+            } else {
+                Err(ServerError::AuthenticationFailed("wrong password".to_string()))
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_the_cap() {
+        let lockout = Lockout::new(LockoutConfig { max_failures: 100, ..config() });
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            delays.push(lockout.record_failure(ip(1), "alice").await.as_millis());
+        }
+        assert_eq!(delays, vec![250, 500, 1000, 1000, 1000]);
+
+        // The larger of the IP and username counts decides
+        assert_eq!(lockout.record_failure(ip(2), "bob").await, Duration::from_millis(250));
+        assert_eq!(lockout.record_failure(ip(3), "alice").await, Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_an_ip_until_the_ban_expires() {
+        let lockout = Lockout::new(config());
+        for username in ["a", "b", "c"] {
+            lockout.record_failure(ip(1), username).await;
+        }
+        assert!(!lockout.is_banned(&ip(1)));
+        lockout.record_failure(ip(1), "d").await;
+        assert!(lockout.is_banned(&ip(1)));
+        assert!(lockout.is_banned(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!lockout.is_banned(&ip(2)));
+
+        tokio::time::advance(Duration::from_secs(599)).await;
+        assert!(lockout.is_banned(&ip(1)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!lockout.is_banned(&ip(1)));
+        // The count restarted with the ban
+        lockout.record_failure(ip(1), "e").await;
+        assert!(!lockout.is_banned(&ip(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_usernames_only_when_asked_to() {
+        let lockout = Lockout::new(config());
+        for last in 1..=4 {
+            lockout.record_failure(ip(last), "alice").await;
+        }
+        assert!(!lockout.is_user_banned("alice"));
+
+        let lockout = Lockout::new(LockoutConfig { ban_usernames: true, ..config() });
+        for last in 1..=4 {
+            lockout.record_failure(ip(last), "alice").await;
+        }
+        assert!(lockout.is_user_banned("alice"));
+        assert!(!lockout.is_user_banned("bob"));
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert!(!lockout.is_user_banned("alice"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_reset_after_a_quiet_window() {
+        let lockout = Lockout::new(config());
+        for username in ["a", "b", "c"] {
+            lockout.record_failure(ip(1), username).await;
+        }
+        tokio::time::advance(Duration::from_secs(899)).await;
+        // Still within the window, measured from the last failure
+        assert_eq!(lockout.record_failure(ip(1), "d").await, Duration::from_millis(1000));
+        assert!(lockout.is_banned(&ip(1)));
+
+        let lockout = Lockout::new(config());
+        for username in ["a", "b", "c"] {
+            lockout.record_failure(ip(1), username).await;
+        }
+        tokio::time::advance(Duration::from_secs(900)).await;
+        assert_eq!(lockout.record_failure(ip(1), "d").await, Duration::from_millis(250));
+        assert!(!lockout.is_banned(&ip(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_clears_the_username_count() {
+        let lockout = Arc::new(Lockout::new(LockoutConfig { ban_usernames: true, ..config() }));
+        let auth = LockoutAuthenticator::new(Arc::new(Backend { down: false }), lockout.clone());
+        let client = |last| SocketAddr::new(ip(last), 40000);
+
+        for last in 1..=3 {
+            assert!(auth.authenticate(client(last), "alice", "wrong").await.is_err());
+        }
+        assert!(auth.authenticate(client(4), "alice", "right").await.is_ok());
+        assert!(auth.authenticate(client(5), "alice", "wrong").await.is_err());
+        assert!(!lockout.is_user_banned("alice"));
+        // The count of the IP is kept
+        for _ in 0..3 {
+            let _ = auth.authenticate(client(1), "bob", "wrong").await;
+        }
+        assert!(lockout.is_banned(&ip(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn outages_are_not_counted() {
+        let lockout = Arc::new(Lockout::new(config()));
+        let auth = LockoutAuthenticator::new(Arc::new(Backend { down: true }), lockout.clone());
+        let client = SocketAddr::new(ip(1), 40000);
+
+        let start = Instant::now();
+        for _ in 0..10 {
+            match auth.authenticate(client, "alice", "right").await {
+                Err(ServerError::AuthBackendUnavailable(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        // Not delayed, not banned
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(!lockout.is_banned(&ip(1)));
+        assert_eq!(lockout.record_failure(ip(1), "alice").await, Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_logins_are_delayed_and_banned_users_refused() {
+        let lockout = Arc::new(Lockout::new(LockoutConfig { ban_usernames: true, ..config() }));
+        let auth = LockoutAuthenticator::new(Arc::new(Backend { down: false }), lockout);
+
+        let start = Instant::now();
+        for last in 1..=4 {
+            assert!(auth.authenticate(SocketAddr::new(ip(last), 40000), "alice", "wrong").await.is_err());
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(250 + 500 + 1000 + 1000));
+        // Banned: even the right password is refused without waiting
+        let start = Instant::now();
+        assert!(auth.authenticate(SocketAddr::new(ip(9), 40000), "alice", "right").await.is_err());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -1,5 +1,11 @@
 use clap::Parser;
+use rusk_socks5::auth_ldap::LdapConfig;
+use rusk_socks5::auth_radius::RadiusConfig;
+use rusk_socks5::auth_webhook::WebhookConfig;
 use rusk_socks5::cli::CliArgs;
+use rusk_socks5::lockout::LockoutConfig;
+use rusk_socks5::server::{ServerConfig, SocksServer};
+use std::time::Duration;
 use env_logger::{Builder, Env};
 
 #[tokio::main]
@@ -10,26 +16,125 @@ async fn main() {
     let args = CliArgs::parse();
 
 
-    let config = rusk_socks5::server::ServerConfig {
+    let config = match server_config(args) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut server = match SocksServer::new(config).await {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("Failed to start server: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+
+    if let Err(e) = server.start().await {
+        log::error!("Failed to start server: {}", e);
+        std::process::exit(1);
+    }
+
+
+}
+
+
+/// Builds the server settings from the command line, reading secret files.
+fn server_config(args: CliArgs) -> Result<ServerConfig, String> {
+    let ldap_bind_password = args
+        .ldap_bind_password_file
+        .as_deref()
+        .map(|path| read_secret_file(path, "LDAP bind password"))
+        .transpose()?;
+    let radius_secret = args
+        .radius_secret_file
+        .as_deref()
+        .map(|path| read_secret_file(path, "RADIUS secret"))
+        .transpose()?;
+
+    Ok(ServerConfig {
         address: args.address,
         port: args.port,
         allow_anonymous: args.allow_anonymous,
+        disable_socks4: args.disable_socks4,
         username: args.username,
         password: args.password,
+        users_file: args.users_file,
+        auth_webhook: args.auth_webhook_url.map(|url| WebhookConfig {
+            url,
+            timeout: Duration::from_millis(args.auth_webhook_timeout_ms),
+            cache_ttl: Duration::from_secs(args.auth_webhook_cache_ttl_secs),
+            negative_cache_ttl: Duration::from_secs(args.auth_webhook_negative_cache_ttl_secs),
+            fail_open: args.auth_webhook_fail_open,
+        }),
+        auth_ldap: args.ldap_url.map(|url| LdapConfig {
+            url,
+            bind_dn: args.ldap_bind_dn,
+            bind_password: ldap_bind_password,
+            base_dn: args.ldap_base_dn.unwrap_or_default(),
+            user_filter: args.ldap_user_filter,
+            required_group: args.ldap_required_group,
+            pool_size: args.ldap_pool_size,
+            timeout: Duration::from_millis(args.ldap_timeout_ms),
+            cache_ttl: Duration::from_secs(args.ldap_cache_ttl_secs),
+            negative_cache_ttl: Duration::from_secs(args.ldap_negative_cache_ttl_secs),
+        }),
+        auth_radius: (!args.radius_server.is_empty()).then(|| RadiusConfig {
+            servers: args.radius_server,
+            secret: radius_secret.unwrap_or_default(),
+            timeout: Duration::from_millis(args.radius_timeout_ms),
+            retries: args.radius_retries,
+            nas_identifier: args.radius_nas_identifier,
+        }),
+        auth_lockout: (args.auth_max_failures > 0).then(|| LockoutConfig {
+            max_failures: args.auth_max_failures,
+            ban_duration: Duration::from_secs(args.auth_ban_secs),
+            base_delay: Duration::from_millis(args.auth_backoff_base_ms),
+            max_delay: Duration::from_millis(args.auth_backoff_max_ms),
+            window: Duration::from_secs(args.auth_failure_window_secs),
+            ban_usernames: args.auth_ban_usernames,
+        }),
         dns_cache_capacity: args.dns_cache_capacity,
         dns_cache_ttl_secs: args.dns_cache_ttl_secs,
+        dns_min_ttl_secs: args.dns_min_ttl_secs,
+        dns_max_ttl_secs: args.dns_max_ttl_secs,
+        dns_negative_ttl_secs: args.dns_negative_ttl_secs,
+        dns_servers: args.dns_server,
+        dns_timeout_ms: args.dns_timeout_ms,
+        dns_attempts: args.dns_attempts,
+        dns_tcp: args.dns_tcp,
+        dns_over_tls: args.dns_over_tls,
+        dns_over_https: args.dns_over_https,
+        dns_tls_ca_file: args.dns_tls_ca_file,
         max_connections: args.max_connections,
+        bind_timeout_secs: args.bind_timeout_secs,
+        connect_timeout_ms: args.connect_timeout_ms,
+        connect_attempt_delay_ms: args.connect_attempt_delay_ms,
         ip_whitelist: args.ip_whitelist,
-    };
-
-    let mut server = rusk_socks5::server::SocksServer::new(config).await.unwrap();
-
-
-    if let Err(e) = server.start().await {
-        log::error!("Failed to start server: {}", e);
-    }
-
+        ip_blacklist: args.ip_blacklist,
+        ip_whitelist_files: args.ip_whitelist_file,
+        ip_blacklist_files: args.ip_blacklist_file,
+        ip_rules_poll_secs: args.ip_rules_poll_secs,
+        dest_rules: args.dest_rule,
+        dest_default_deny: args.dest_default_deny,
+        disable_ssrf_protection: args.disable_ssrf_protection,
+        private_dest_allow: args.private_dest_allow,
+        geoip_country_db: args.geoip_country_db,
+        geoip_asn_db: args.geoip_asn_db,
+        geo_source_allow: args.geo_source_allow,
+        geo_source_deny: args.geo_source_deny,
+        geo_dest_allow: args.geo_dest_allow,
+        geo_dest_deny: args.geo_dest_deny,
+    })
+}
 
+fn read_secret_file(path: &str, what: &str) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|secret| secret.trim_end().to_string())
+        .map_err(|e| format!("Failed to read {} file {}: {}", what, path, e))
 }
 
 
@@ -0,0 +1,226 @@
+use crate::dns_message::{self, Response, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+use crate::errors::{Result, ServerError};
+use async_trait::async_trait;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const DEFAULT_PORT: u16 = 53;
+const MAX_UDP_RESPONSE: usize = 4096;
+
+/// The outcome of a successful lookup.
+#[derive(Debug, Clone, Default)]
+pub struct Lookup {
+    /// Empty when the name does not exist (NXDOMAIN).
+    pub addrs: Vec<IpAddr>,
+    /// How long the answer may be cached, if the resolver knows.
+    pub ttl: Option<Duration>,
+}
+
+/// Turns host names into addresses for [`crate::dns_cache::DnsCache`].
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Returns the addresses of `host`. Failures are reported as
+    /// [`ServerError::HostUnreachable`].
+    async fn lookup(&self, host: &str) -> Result<Lookup>;
+}
+
+/// Resolves through the operating system (getaddrinfo on a blocking thread),
+/// honoring /etc/hosts and /etc/resolv.conf.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup(&self, host: &str) -> Result<Lookup> {
+        let addrs = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| ServerError::HostUnreachable(format!("{}: {}", host, e)))?;
+        // getaddrinfo reports neither TTLs nor NXDOMAIN as such
+        Ok(Lookup {
+            addrs: addrs.map(|a| a.ip()).collect(),
+            ttl: None,
+        })
+    }
+}
+
+/// A nameserver together with the transport used to reach it.
+#[async_trait]
+pub trait Upstream: Send + Sync + fmt::Display {
+    /// Sends `query` and returns the server's answer to it.
+    async fn exchange(&self, query: &[u8]) -> std::result::Result<Response, String>;
+}
+
+/// Plain DNS over UDP, retried over TCP when the answer is truncated.
+pub struct PlainUpstream {
+    addr: SocketAddr,
+    tcp_only: bool,
+}
+
+impl PlainUpstream {
+    pub fn new(addr: SocketAddr, tcp_only: bool) -> Self {
+        PlainUpstream { addr, tcp_only }
+    }
+
+    /// Parses `ip`, `ip:port` or `[ipv6]:port`; the port defaults to 53.
+    pub fn parse(server: &str, tcp_only: bool) -> std::result::Result<Self, String> {
+        let addr = parse_socket_addr(server, DEFAULT_PORT)
+            .ok_or_else(|| format!("Invalid DNS server address: {}", server))?;
+        Ok(PlainUpstream::new(addr, tcp_only))
+    }
+}
+
+impl fmt::Display for PlainUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+#[async_trait]
+impl Upstream for PlainUpstream {
+    async fn exchange(&self, query: &[u8]) -> std::result::Result<Response, String> {
+        if !self.tcp_only {
+            let response = udp_exchange(self.addr, query).await?;
+            if !response.truncated {
+                return Ok(response);
+            }
+        }
+        tcp_exchange(self.addr, query).await
+    }
+}
+
+/// Parses `ip`, `ip:port` or `[ipv6]:port`.
+pub(crate) fn parse_socket_addr(s: &str, default_port: u16) -> Option<SocketAddr> {
+    s.parse::<SocketAddr>()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, default_port)))
+}
+
+/// Settings for [`StubResolver`].
+#[derive(Clone)]
+pub struct StubConfig {
+    /// Recursive nameservers, queried round-robin.
+    pub upstreams: Vec<Arc<dyn Upstream>>,
+    /// Time to wait for one server to answer.
+    pub timeout: Duration,
+    /// Queries sent (to successive servers) before giving up.
+    pub attempts: u32,
+}
+
+/// Asks recursive nameservers directly instead of going through the system
+/// resolver. A and AAAA are queried in parallel. Names are sent as given:
+/// no search domains and no /etc/hosts.
+pub struct StubResolver {
+    config: StubConfig,
+    next: AtomicUsize,
+}
+
+impl StubResolver {
+    pub fn new(config: StubConfig) -> Result<Self> {
+        if config.upstreams.is_empty() {
+            return Err(ServerError::Unknown("No DNS server configured".to_string()));
+        }
+        Ok(StubResolver {
+            config,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends one query, moving on to the next server on timeouts, network
+    /// errors and server failures.
+    async fn query(&self, name: &str, qtype: u16) -> std::result::Result<Response, String> {
+        let mut last_err = String::new();
+        // Start at the next server in turn and retry on the ones after it,
+        // so a retry does not go back to a server that just failed
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        for attempt in 0..self.config.attempts.max(1) as usize {
+            let index = first.wrapping_add(attempt) % self.config.upstreams.len();
+            let upstream = &self.config.upstreams[index];
+            let query = dns_message::build_query(rand::random(), name, qtype)?;
+
+            let result = match tokio::time::timeout(self.config.timeout, upstream.exchange(&query)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", self.config.timeout)),
+            };
+
+            match result {
+                Ok(response) if matches!(response.rcode, RCODE_NOERROR | RCODE_NXDOMAIN) => {
+                    return Ok(response);
+                }
+                Ok(response) => last_err = format!("{} answered with rcode {}", upstream, response.rcode),
+                Err(e) => last_err = format!("{}: {}", upstream, e),
+            }
+            log::debug!("DNS query for {} failed: {}", name, last_err);
+        }
+        Err(last_err)
+    }
+}
+
+async fn udp_exchange(server: SocketAddr, query: &[u8]) -> std::result::Result<Response, String> {
+    let bind_address = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_address).await.map_err(|e| e.to_string())?;
+    socket.connect(server).await.map_err(|e| e.to_string())?;
+    socket.send(query).await.map_err(|e| e.to_string())?;
+
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let n = socket.recv(&mut buf).await.map_err(|e| e.to_string())?;
+        // Ignore stray datagrams that do not answer this query
+        match dns_message::parse_response(&buf[..n], query) {
+            Ok(response) => return Ok(response),
+            Err(e) => log::debug!("Ignoring DNS response from {}: {}", server, e),
+        }
+    }
+}
+
+async fn tcp_exchange(server: SocketAddr, query: &[u8]) -> std::result::Result<Response, String> {
+    let mut stream = TcpStream::connect(server).await.map_err(|e| e.to_string())?;
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await.map_err(|e| e.to_string())?;
+
+    let len = stream.read_u16().await.map_err(|e| e.to_string())? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await.map_err(|e| e.to_string())?;
+    dns_message::parse_response(&buf, query)
+}
+
+#[async_trait]
+impl Resolver for StubResolver {
+    async fn lookup(&self, host: &str) -> Result<Lookup> {
+        let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+
+        let mut lookup = Lookup::default();
+        let mut nxdomain = false;
+        let mut errors = Vec::new();
+        for result in [v4, v6] {
+            match result {
+                Ok(response) => {
+                    nxdomain |= response.rcode == RCODE_NXDOMAIN;
+                    lookup.addrs.extend(response.addrs);
+                    if let Some(ttl) = response.ttl.map(|t| Duration::from_secs(t.into())) {
+                        lookup.ttl = Some(lookup.ttl.map_or(ttl, |t| t.min(ttl)));
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if !lookup.addrs.is_empty() {
+            return Ok(lookup);
+        }
+        if nxdomain {
+            // Only the caller's negative TTL applies
+            return Ok(Lookup::default());
+        }
+        let reason = errors.pop().unwrap_or_else(|| "no addresses".to_string());
+        Err(ServerError::HostUnreachable(format!("{}: {}", host, reason)))
+    }
+}
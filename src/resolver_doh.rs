@@ -0,0 +1,101 @@
+use crate::dns_message::{self, Response};
+use crate::resolver::Upstream;
+use async_trait::async_trait;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{Certificate, Client, Url};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS over HTTPS (RFC 8484), POSTing wire-format queries. The client keeps
+/// its connection open and negotiates HTTP/2, so concurrent queries are
+/// multiplexed over a single connection.
+pub struct DohUpstream {
+    url: Url,
+    client: Client,
+}
+
+impl DohUpstream {
+    /// Parses `https://host/path#ip` or `https://ip/path`. The `#ip` is the
+    /// address to connect to and is required for host names, so that the
+    /// resolver's own name is never looked up in plaintext. The certificate
+    /// is checked against the roots in `ca_file` (PEM) if given, otherwise
+    /// against the bundled Mozilla roots.
+    pub fn parse(spec: &str, ca_file: Option<&str>) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("Invalid DNS-over-HTTPS URL {}: {}", spec, reason);
+        let mut url = Url::parse(spec).map_err(|e| invalid(&e.to_string()))?;
+        if url.scheme() != "https" {
+            return Err(invalid("scheme must be https"));
+        }
+        let pinned = url
+            .fragment()
+            .map(|ip| ip.parse::<IpAddr>().map_err(|_| invalid("fragment must be an IP address")))
+            .transpose()?;
+        url.set_fragment(None);
+        let host = url.host_str().ok_or_else(|| invalid("missing host"))?;
+        // IPv6 hosts keep their brackets
+        let host = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(host.to_string()),
+        };
+        if host.is_some() && pinned.is_none() {
+            return Err(invalid("append the server's IP address, e.g. https://dns.example/dns-query#192.0.2.53"));
+        }
+
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .https_only(true)
+            .no_proxy()
+            .pool_idle_timeout(Duration::from_secs(300))
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true);
+        if let Some(path) = ca_file {
+            let pem = std::fs::read(path).map_err(|e| format!("Failed to read CA file {}: {}", path, e))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("Failed to read CA file {}: {}", path, e))?;
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let (Some(host), Some(ip)) = (host, pinned) {
+            let port = url.port_or_known_default().unwrap_or(443);
+            builder = builder.resolve(&host, SocketAddr::new(ip, port));
+        }
+        let client = builder.build().map_err(|e| invalid(&e.to_string()))?;
+        Ok(DohUpstream { url, client })
+    }
+}
+
+impl fmt::Display for DohUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+#[async_trait]
+impl Upstream for DohUpstream {
+    async fn exchange(&self, query: &[u8]) -> Result<Response, String> {
+        // RFC 8484 recommends ID 0, which keeps answers cacheable by HTTP caches
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&[0, 0]);
+
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(query.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("HTTP {}", status));
+        }
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        dns_message::parse_response(&body, &query)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::dns_message::{self, Response};
+use crate::resolver::{parse_socket_addr, Upstream};
+use async_trait::async_trait;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const DEFAULT_PORT: u16 = 853;
+
+/// Builds the TLS settings for DNS-over-TLS upstreams. Certificates are
+/// checked against the roots in `ca_file` (PEM) if given, otherwise against
+/// the bundled Mozilla roots.
+pub fn tls_config(ca_file: Option<&str>) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Failed to read CA file {}: {}", path, e))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("No usable CA certificate in {}", path));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// DNS over TLS (RFC 7858). Queries share one long-lived connection and are
+/// pipelined: each is written as soon as it is made and answers are matched
+/// by message ID, in whatever order the server sends them. The connection is
+/// re-established on the next query after the server closes it, or after a
+/// query is given up on with nothing heard from the server since it was sent.
+pub struct DotUpstream {
+    addr: SocketAddr,
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl DotUpstream {
+    pub fn new(addr: SocketAddr, name: ServerName<'static>, tls: Arc<ClientConfig>) -> Self {
+        DotUpstream {
+            addr,
+            name,
+            connector: TlsConnector::from(tls),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Parses `ip[:port][#name]`; the port defaults to 853. The certificate
+    /// must be valid for `name`, or for the IP address when no name is given.
+    pub fn parse(spec: &str, tls: Arc<ClientConfig>) -> Result<Self, String> {
+        let invalid = || format!("Invalid DNS-over-TLS server (expected IP[:PORT][#NAME]): {}", spec);
+        let (address, name) = match spec.split_once('#') {
+            Some((address, name)) => (address, Some(name)),
+            None => (spec, None),
+        };
+        let addr = parse_socket_addr(address, DEFAULT_PORT).ok_or_else(invalid)?;
+        let name = match name {
+            Some(name) => ServerName::try_from(name.to_string()).map_err(|_| invalid())?,
+            None => ServerName::from(addr.ip()),
+        };
+        Ok(DotUpstream::new(addr, name, tls))
+    }
+
+    /// Returns the open connection, connecting first if there is none.
+    async fn connection(&self) -> Result<Arc<Connection>, String> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref()
+            && !connection.is_closed()
+        {
+            return Ok(connection.clone());
+        }
+
+        let tcp = TcpStream::connect(self.addr).await.map_err(|e| e.to_string())?;
+        let tls = self
+            .connector
+            .connect(self.name.clone(), tcp)
+            .await
+            .map_err(|e| format!("TLS handshake failed: {}", e))?;
+        log::debug!("Connected to DNS-over-TLS server {}", self);
+        let (reader, writer) = tokio::io::split(tls);
+        let (outgoing, queue) = mpsc::unbounded_channel();
+        let connection = Arc::new(Connection {
+            outgoing,
+            pending: Mutex::new(Pending::default()),
+            next_id: AtomicU16::new(rand::random()),
+            shutdown: Notify::new(),
+        });
+        tokio::spawn(write_queries(writer, queue, Arc::downgrade(&connection)));
+        tokio::spawn(read_answers(reader, connection.clone()));
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+impl fmt::Display for DotUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tls://{}#{}", self.addr, self.name.to_str())
+    }
+}
+
+#[async_trait]
+impl Upstream for DotUpstream {
+    async fn exchange(&self, query: &[u8]) -> Result<Response, String> {
+        let connection = self.connection().await?;
+        // IDs must be unique among the queries in flight on the connection,
+        // so the query is renumbered
+        let (id, answers, answer) = connection.register()?;
+        let guard = PendingGuard { connection: &connection, id, answers };
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&id.to_be_bytes());
+
+        let mut message = Vec::with_capacity(query.len() + 2);
+        message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        message.extend_from_slice(&query);
+        // Writing happens on a separate task, so a query abandoned on
+        // timeout never leaves half a message on the connection
+        connection
+            .outgoing
+            .send(message)
+            .map_err(|_| "connection closed".to_string())?;
+
+        let answer = answer.await.map_err(|_| "connection closed".to_string())?;
+        drop(guard);
+        dns_message::parse_response(&answer, &query)
+    }
+}
+
+struct Connection {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    pending: Mutex<Pending>,
+    next_id: AtomicU16,
+    /// Stops the reader task.
+    shutdown: Notify,
+}
+
+/// Queries waiting for an answer. Once `closed` is set no more are accepted.
+#[derive(Default)]
+struct Pending {
+    waiters: HashMap<u16, oneshot::Sender<Vec<u8>>>,
+    /// Messages read from the server so far.
+    answers: u64,
+    closed: bool,
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    /// Fails all waiting queries and stops reading.
+    fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.closed = true;
+        pending.waiters.clear();
+        self.shutdown.notify_one();
+    }
+
+    /// Returns the ID for a new query, the number of messages read so far
+    /// and where the answer will be delivered.
+    fn register(&self) -> Result<(u16, u64, oneshot::Receiver<Vec<u8>>), String> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return Err("connection closed".to_string());
+        }
+        let id = loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if !pending.waiters.contains_key(&id) {
+                break id;
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        pending.waiters.insert(id, tx);
+        Ok((id, pending.answers, rx))
+    }
+}
+
+/// Forgets a query that was answered, failed or given up on (the resolver
+/// drops the exchange on timeout).
+struct PendingGuard<'a> {
+    connection: &'a Connection,
+    id: u16,
+    /// Messages read before the query was sent.
+    answers: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.connection.pending.lock().unwrap();
+        // Given up on while the server stayed silent: it has stopped
+        // answering on this connection, so the next query reconnects
+        let stalled = pending.waiters.remove(&self.id).is_some() && pending.answers == self.answers;
+        drop(pending);
+        if stalled {
+            log::debug!("DNS-over-TLS server stopped answering, dropping the connection");
+            self.connection.close();
+        }
+    }
+}
+
+async fn write_queries(
+    mut writer: WriteHalf<TlsStream<TcpStream>>,
+    mut queue: mpsc::UnboundedReceiver<Vec<u8>>,
+    connection: Weak<Connection>,
+) {
+    while let Some(message) = queue.recv().await {
+        if let Err(e) = writer.write_all(&message).await {
+            log::debug!("Failed to write to DNS-over-TLS connection: {}", e);
+            if let Some(connection) = connection.upgrade() {
+                connection.close();
+            }
+            return;
+        }
+    }
+}
+
+async fn read_answers(mut reader: ReadHalf<TlsStream<TcpStream>>, connection: Arc<Connection>) {
+    loop {
+        let read = tokio::select! {
+            read = read_message(&mut reader) => read,
+            _ = connection.shutdown.notified() => break,
+        };
+        let buf = match read {
+            Ok(buf) if buf.len() >= 2 => buf,
+            Ok(_) => break,
+            Err(e) => {
+                log::debug!("DNS-over-TLS connection closed: {}", e);
+                break;
+            }
+        };
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let waiter = {
+            let mut pending = connection.pending.lock().unwrap();
+            pending.answers += 1;
+            pending.waiters.remove(&id)
+        };
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(buf);
+        }
+    }
+    connection.close();
+}
+
+async fn read_message(reader: &mut ReadHalf<TlsStream<TcpStream>>) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
@@ -0,0 +1,317 @@
+use crate::ip_filter::IpFilter;
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Where the source IP rules come from: rules given on the command line plus
+/// rule files, which can change while the server runs.
+#[derive(Debug, Clone, Default)]
+pub struct IpRuleSource {
+    pub whitelist: Vec<String>,
+    pub blacklist: Vec<String>,
+    pub whitelist_files: Vec<String>,
+    pub blacklist_files: Vec<String>,
+}
+
+impl IpRuleSource {
+    pub fn load(&self) -> Result<IpFilter, String> {
+        self.load_counted().map(|(filter, _)| filter)
+    }
+
+    /// Loads the rules, also returning how many each whitelist file
+    /// contributed.
+    fn load_counted(&self) -> Result<(IpFilter, Vec<usize>), String> {
+        let mut filter = IpFilter::from_lists(&self.whitelist, &self.blacklist)?;
+        let mut counts = Vec::new();
+        for path in &self.whitelist_files {
+            let before = filter.whitelist_len();
+            filter.add_rules_file(path)?;
+            counts.push(filter.whitelist_len() - before);
+        }
+        for path in &self.blacklist_files {
+            filter.add_deny_rules_file(path)?;
+        }
+        Ok((filter, counts))
+    }
+
+    fn files(&self) -> impl Iterator<Item = &String> {
+        self.whitelist_files.iter().chain(&self.blacklist_files)
+    }
+
+    pub fn has_files(&self) -> bool {
+        !self.whitelist_files.is_empty() || !self.blacklist_files.is_empty()
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Reloads the rule files on SIGHUP and, when `poll` is set, once one of
+/// them has a new modification time that stayed the same for a whole poll
+/// interval, so a file still being written is not picked up. The new filter
+/// replaces the old one in a single atomic store.
+///
+/// The rules in use are kept if a file cannot be read or parsed, or if a
+/// whitelist file that had rules now has none: an empty whitelist would allow
+/// every client. Emptying a blacklist file takes effect like any other
+/// change. Files should still be updated by writing a new file and renaming
+/// it over the old one, so that a reload never sees a partial file.
+pub async fn watch(source: IpRuleSource, filter: Arc<ArcSwap<IpFilter>>, poll: Option<Duration>) {
+    let mut hangup = Hangup::new();
+    let mut reloader = Reloader::new(source, filter);
+
+    loop {
+        let reason = tokio::select! {
+            _ = hangup.recv() => "SIGHUP",
+            _ = sleep(poll) => {
+                if !reloader.changed() {
+                    continue;
+                }
+                "file change"
+            }
+        };
+        reloader.reload(reason);
+    }
+}
+
+/// What [`watch`] remembers between reloads.
+struct Reloader {
+    source: IpRuleSource,
+    filter: Arc<ArcSwap<IpFilter>>,
+    /// Modification times of the files as last loaded.
+    loaded: Vec<Option<SystemTime>>,
+    /// Modification times at the previous poll.
+    seen: Vec<Option<SystemTime>>,
+    /// Rules each whitelist file had when last loaded.
+    whitelist_counts: Vec<usize>,
+}
+
+impl Reloader {
+    fn new(source: IpRuleSource, filter: Arc<ArcSwap<IpFilter>>) -> Self {
+        let loaded = source.modified_times();
+        let whitelist_counts = source.load_counted().map(|(_, counts)| counts).unwrap_or_default();
+        Reloader {
+            source,
+            filter,
+            seen: loaded.clone(),
+            loaded,
+            whitelist_counts,
+        }
+    }
+
+    /// Checks the modification times, once per poll interval. True if they
+    /// differ from the loaded ones and have not changed since the last check.
+    fn changed(&mut self) -> bool {
+        let current = self.source.modified_times();
+        if current != self.seen {
+            // Wait for the file to stop changing
+            self.seen = current;
+            return false;
+        }
+        current != self.loaded
+    }
+
+    /// Loads the rules again and installs them, returning whether the filter
+    /// was replaced.
+    fn reload(&mut self, reason: &str) -> bool {
+        self.loaded = self.source.modified_times();
+        self.seen = self.loaded.clone();
+
+        match self.source.load_counted() {
+            Ok((new_filter, new_counts)) => {
+                if let Some(path) = self
+                    .source
+                    .whitelist_files
+                    .iter()
+                    .zip(self.whitelist_counts.iter().zip(&new_counts))
+                    .find(|(_, (old, new))| **old > 0 && **new == 0)
+                    .map(|(path, _)| path)
+                {
+                    log::error!(
+                        "IP whitelist file {} is now empty, keeping the previous rules (replace rule files by atomic rename)",
+                        path
+                    );
+                    return false;
+                }
+                self.whitelist_counts = new_counts;
+                log::info!(
+                    "Reloaded IP rules after {}: whitelist_rules={}, blacklist_rules={}",
+                    reason,
+                    new_filter.whitelist_len(),
+                    new_filter.blacklist_len()
+                );
+                self.filter.store(Arc::new(new_filter));
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to reload IP rules, keeping the previous ones: {}", e);
+                false
+            }
+        }
+    }
+}
+
+async fn sleep(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// SIGHUP listener; never fires on platforms without signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| log::warn!("Cannot listen for SIGHUP: {}", e))
+                .ok();
+            Hangup { signal }
+        }
+        #[cfg(not(unix))]
+        Hangup {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    /// A directory of rule files, removed when dropped.
+    struct RuleDir(PathBuf);
+
+    impl RuleDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rusk-socks5-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            RuleDir(dir)
+        }
+
+        /// Writes `contents` to `name`, with a modification time of `mtime`
+        /// seconds after the epoch so tests do not depend on clock resolution.
+        fn write(&self, name: &str, contents: &str, mtime: u64) -> String {
+            let path = self.0.join(name);
+            std::fs::write(&path, contents).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)).unwrap();
+            path.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for RuleDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn allows(filter: &ArcSwap<IpFilter>, ip: &str) -> bool {
+        filter.load().allows(&ip.parse::<IpAddr>().unwrap())
+    }
+
+    fn reloader(source: IpRuleSource) -> (Reloader, Arc<ArcSwap<IpFilter>>) {
+        let filter = Arc::new(ArcSwap::from_pointee(source.load().unwrap()));
+        (Reloader::new(source, filter.clone()), filter)
+    }
+
+    #[test]
+    fn reloads_once_the_modification_time_is_stable() {
+        let dir = RuleDir::new("stable-mtime");
+        let path = dir.write("allow.txt", "192.0.2.1\n", 1000);
+        let (mut reloader, filter) = reloader(IpRuleSource {
+            whitelist_files: vec![path],
+            ..Default::default()
+        });
+        assert!(!reloader.changed());
+
+        dir.write("allow.txt", "192.0.2.2\n", 2000);
+        // First poll sees the new time, the second confirms it stayed put
+        assert!(!reloader.changed());
+        dir.write("allow.txt", "192.0.2.3\n", 3000);
+        assert!(!reloader.changed());
+        assert!(reloader.changed());
+        assert!(reloader.reload("file change"));
+        assert!(allows(&filter, "192.0.2.3"));
+        assert!(!allows(&filter, "192.0.2.1"));
+        assert!(!reloader.changed());
+    }
+
+    #[test]
+    fn keeps_the_rules_when_a_file_does_not_parse() {
+        let dir = RuleDir::new("parse-error");
+        let path = dir.write("deny.txt", "192.0.2.1\n", 1000);
+        let (mut reloader, filter) = reloader(IpRuleSource {
+            blacklist_files: vec![path],
+            ..Default::default()
+        });
+
+        dir.write("deny.txt", "192.0.2.2\nnot-an-ip\n", 2000);
+        assert!(!reloader.reload("SIGHUP"));
+        assert!(!allows(&filter, "192.0.2.1"));
+        assert!(allows(&filter, "192.0.2.2"));
+        // Not retried until the file changes again
+        assert!(!reloader.changed());
+    }
+
+    #[test]
+    fn keeps_the_rules_when_a_whitelist_file_is_emptied() {
+        let dir = RuleDir::new("empty-whitelist");
+        let allow = dir.write("allow.txt", "192.0.2.1\n", 1000);
+        let deny = dir.write("deny.txt", "198.51.100.1\n", 1000);
+        let (mut reloader, filter) = reloader(IpRuleSource {
+            whitelist_files: vec![allow],
+            blacklist_files: vec![deny],
+            ..Default::default()
+        });
+
+        dir.write("allow.txt", "# all gone\n", 2000);
+        assert!(!reloader.reload("SIGHUP"));
+        assert!(!allows(&filter, "203.0.113.1"));
+
+        // An empty blacklist only lets more clients in
+        dir.write("allow.txt", "192.0.2.1\n198.51.100.1\n", 3000);
+        dir.write("deny.txt", "", 3000);
+        assert!(reloader.reload("SIGHUP"));
+        assert!(allows(&filter, "198.51.100.1"));
+        assert!(!allows(&filter, "203.0.113.1"));
+    }
+
+    #[test]
+    fn sighup_reloads_unchanged_files() {
+        let dir = RuleDir::new("sighup");
+        let path = dir.write("deny.txt", "192.0.2.1\n", 1000);
+        let (mut reloader, filter) = reloader(IpRuleSource {
+            blacklist: vec!["198.51.100.1".to_string()],
+            blacklist_files: vec![path],
+            ..Default::default()
+        });
+
+        // Same modification time, so polling would not notice
+        dir.write("deny.txt", "192.0.2.2\n", 1000);
+        assert!(!reloader.changed());
+        assert!(reloader.reload("SIGHUP"));
+        assert!(allows(&filter, "192.0.2.1"));
+        assert!(!allows(&filter, "192.0.2.2"));
+        // Command-line rules stay
+        assert!(!allows(&filter, "198.51.100.1"));
+    }
+}
@@ -3,21 +3,69 @@ use crate::errors::{Result, ServerError};
 use log;
 use tokio::net::TcpListener;
 use crate::handlers::ConnectionHandler;
-use crate::dns_cache::DnsCache;
+use crate::http_proxy::{looks_like_http, HttpProxyHandler};
+use crate::dns_cache::{DnsCache, DnsCacheConfig};
+use crate::resolver::{PlainUpstream, Resolver, StubConfig, StubResolver, SystemResolver, Upstream};
+use crate::resolver_doh::DohUpstream;
+use crate::resolver_dot::{self, DotUpstream};
 use std::time::Duration;
 use crate::ip_filter::IpFilter;
+use crate::geoip::{GeoFilter, GeoIpDb};
+use crate::rule_reload::{self, IpRuleSource};
+use arc_swap::ArcSwap;
+use crate::connector::{Connector, ConnectorConfig};
+use crate::dest_filter::{Action, DestFilter};
+use crate::auth::{AnonymousAuthenticator, Authenticator, StaticAuthenticator};
+use crate::auth_ldap::{LdapAuthenticator, LdapConfig};
+use crate::auth_radius::{RadiusAuthenticator, RadiusConfig};
+use crate::auth_webhook::{WebhookAuthenticator, WebhookConfig};
+use crate::lockout::{Lockout, LockoutAuthenticator, LockoutConfig};
+use crate::users::UserStore;
 use tokio::sync::Semaphore;
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub address: String,
     pub port: u16,
     pub allow_anonymous: bool,
+    pub disable_socks4: bool,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub users_file: Option<String>,
+    pub auth_webhook: Option<WebhookConfig>,
+    pub auth_ldap: Option<LdapConfig>,
+    pub auth_radius: Option<RadiusConfig>,
+    pub auth_lockout: Option<LockoutConfig>,
     pub dns_cache_capacity: u64,
     pub dns_cache_ttl_secs: u64,
+    pub dns_min_ttl_secs: u64,
+    pub dns_max_ttl_secs: u64,
+    pub dns_negative_ttl_secs: u64,
+    pub dns_servers: Vec<String>,
+    pub dns_timeout_ms: u64,
+    pub dns_attempts: u32,
+    pub dns_tcp: bool,
+    pub dns_over_tls: Vec<String>,
+    pub dns_over_https: Vec<String>,
+    pub dns_tls_ca_file: Option<String>,
     pub max_connections: usize,
+    pub bind_timeout_secs: u64,
+    pub connect_timeout_ms: u64,
+    pub connect_attempt_delay_ms: u64,
     pub ip_whitelist: Vec<String>,
+    pub ip_blacklist: Vec<String>,
+    pub ip_whitelist_files: Vec<String>,
+    pub ip_blacklist_files: Vec<String>,
+    pub ip_rules_poll_secs: u64,
+    pub dest_rules: Vec<String>,
+    pub dest_default_deny: bool,
+    pub disable_ssrf_protection: bool,
+    pub private_dest_allow: Vec<String>,
+    pub geoip_country_db: Option<String>,
+    pub geoip_asn_db: Option<String>,
+    pub geo_source_allow: Vec<String>,
+    pub geo_source_deny: Vec<String>,
+    pub geo_dest_allow: Vec<String>,
+    pub geo_dest_deny: Vec<String>,
 }
 
 pub struct SocksServer {
@@ -26,55 +74,196 @@ pub struct SocksServer {
     listener: Option<TcpListener>,
     dns_cache: Arc<DnsCache>,
     conn_semaphore: Arc<Semaphore>,
-    ip_filter: Arc<IpFilter>,
+    ip_rules: IpRuleSource,
+    ip_filter: Arc<ArcSwap<IpFilter>>,
+    dest_filter: Arc<DestFilter>,
+    connector: Arc<Connector>,
+    source_geo: Option<GeoFilter>,
+    authenticator: Arc<dyn Authenticator>,
+    lockout: Option<Arc<Lockout>>,
 }
 
 impl SocksServer {
     pub async fn new(config: ServerConfig) -> Result<Self> {
-        let dns_cache = DnsCache::new(
-            config.dns_cache_capacity,
-            Duration::from_secs(config.dns_cache_ttl_secs),
+        if config.dns_min_ttl_secs > config.dns_max_ttl_secs {
+            return Err(ServerError::Unknown(format!(
+                "--dns-min-ttl-secs ({}) is greater than --dns-max-ttl-secs ({})",
+                config.dns_min_ttl_secs, config.dns_max_ttl_secs
+            )));
+        }
+        let resolver = build_resolver(&config).map_err(ServerError::Unknown)?;
+        let dns_cache = DnsCache::with_config(
+            DnsCacheConfig {
+                max_capacity: config.dns_cache_capacity,
+                default_ttl: Duration::from_secs(config.dns_cache_ttl_secs),
+                min_ttl: Duration::from_secs(config.dns_min_ttl_secs),
+                max_ttl: Duration::from_secs(config.dns_max_ttl_secs),
+                negative_ttl: Duration::from_secs(config.dns_negative_ttl_secs),
+            },
+            resolver,
         );
+        let connector = Connector::new(ConnectorConfig {
+            attempt_delay: Duration::from_millis(config.connect_attempt_delay_ms),
+            timeout: Duration::from_millis(config.connect_timeout_ms),
+        });
         let conn_semaphore = Semaphore::new(config.max_connections);
-        let ip_filter = IpFilter::from_strings(&config.ip_whitelist)
-            .map_err(|e| ServerError::Unknown(e))?;
+        let ip_rules = IpRuleSource {
+            whitelist: config.ip_whitelist.clone(),
+            blacklist: config.ip_blacklist.clone(),
+            whitelist_files: config.ip_whitelist_files.clone(),
+            blacklist_files: config.ip_blacklist_files.clone(),
+        };
+        let ip_filter = ip_rules.load().map_err(ServerError::Unknown)?;
+        let default_action = if config.dest_default_deny { Action::Deny } else { Action::Allow };
+        let mut dest_filter = DestFilter::from_strings(&config.dest_rules, default_action)
+            .map_err(ServerError::Unknown)?;
+        dest_filter.set_block_private(!config.disable_ssrf_protection);
+        for pattern in &config.private_dest_allow {
+            dest_filter.allow_private(pattern).map_err(ServerError::Unknown)?;
+        }
+        let mut source_geo = None;
+        if config.geoip_country_db.is_some() || config.geoip_asn_db.is_some() {
+            let db = GeoIpDb::open(config.geoip_country_db.as_deref(), config.geoip_asn_db.as_deref())
+                .map_err(ServerError::Unknown)?;
+            let db = Arc::new(db);
+            let source = GeoFilter::new(db.clone(), &config.geo_source_allow, &config.geo_source_deny)
+                .map_err(ServerError::Unknown)?;
+            let dest = GeoFilter::new(db, &config.geo_dest_allow, &config.geo_dest_deny)
+                .map_err(ServerError::Unknown)?;
+            if !source.is_empty() {
+                source_geo = Some(source);
+            }
+            if !dest.is_empty() {
+                dest_filter.set_geo_filter(dest);
+            }
+        } else if !(config.geo_source_allow.is_empty()
+            && config.geo_source_deny.is_empty()
+            && config.geo_dest_allow.is_empty()
+            && config.geo_dest_deny.is_empty())
+        {
+            return Err(ServerError::Unknown(
+                "GeoIP rules need --geoip-country-db or --geoip-asn-db".to_string(),
+            ));
+        }
+        let mut users = match &config.users_file {
+            Some(path) => UserStore::from_file(path).map_err(ServerError::Unknown)?,
+            None => UserStore::new(),
+        };
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            users.add_password(username, password).map_err(ServerError::Unknown)?;
+        }
+        let mut backends: Vec<Arc<dyn Authenticator>> = Vec::new();
+        if let Some(webhook) = &config.auth_webhook {
+            log::info!("Authenticating users via webhook {}", webhook.url);
+            backends.push(Arc::new(WebhookAuthenticator::new(webhook.clone())?));
+        }
+        if let Some(ldap) = &config.auth_ldap {
+            log::info!("Authenticating users via LDAP {}", ldap.url);
+            backends.push(Arc::new(LdapAuthenticator::new(ldap.clone())));
+        }
+        if let Some(radius) = &config.auth_radius {
+            log::info!("Authenticating users via RADIUS {}", radius.servers.join(", "));
+            backends.push(Arc::new(RadiusAuthenticator::new(radius.clone())?));
+        }
+        if backends.len() > 1 {
+            return Err(ServerError::Unknown(
+                "Only one external authentication backend can be configured".to_string(),
+            ));
+        }
+        if !backends.is_empty() && (config.users_file.is_some() || config.username.is_some()) {
+            return Err(ServerError::Unknown(
+                "Local accounts (--users-file, --username) cannot be combined with an external authentication backend".to_string(),
+            ));
+        }
+        let mut authenticator: Arc<dyn Authenticator> = match backends.pop() {
+            Some(backend) => backend,
+            None => {
+                log::info!("Loaded {} user account(s)", users.len());
+                Arc::new(StaticAuthenticator::new(users))
+            }
+        };
+        let lockout = config.auth_lockout.clone().map(|c| Arc::new(Lockout::new(c)));
+        if let Some(lockout) = &lockout {
+            authenticator = Arc::new(LockoutAuthenticator::new(authenticator, lockout.clone()));
+        }
+        if config.allow_anonymous {
+            authenticator = Arc::new(AnonymousAuthenticator::with_credentials(authenticator));
+        }
         Ok(SocksServer {
             config: Arc::new(config),
             listener: None,
             dns_cache: Arc::new(dns_cache),
             conn_semaphore: Arc::new(conn_semaphore),
-            ip_filter: Arc::new(ip_filter),
+            ip_rules,
+            ip_filter: Arc::new(ArcSwap::from_pointee(ip_filter)),
+            dest_filter: Arc::new(dest_filter),
+            connector: Arc::new(connector),
+            source_geo,
+            authenticator,
+            lockout,
         })
     }
 
+    /// Replaces the authenticator built from `ServerConfig`, e.g. with a
+    /// custom backend when embedding the server as a library. Failed logins
+    /// are still subject to `auth_lockout`.
+    pub fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.authenticator = match &self.lockout {
+            Some(lockout) => Arc::new(LockoutAuthenticator::new(authenticator, lockout.clone())),
+            None => authenticator,
+        };
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         self.listener =
             Some(TcpListener::bind(format!("{}:{}", self.config.address, self.config.port)).await?);
 
         log::info!(
-            "Socks5 server started on {}:{}, dns_cache_capacity={}, ttl_secs={}, max_connections={}, whitelist_rules={}",
+            "Socks5 server started on {}:{}, dns_cache_capacity={}, ttl_secs={}, max_connections={}, whitelist_rules={}, blacklist_rules={}, dest_rules={}",
             self.config.address,
             self.config.port,
             self.config.dns_cache_capacity,
             self.config.dns_cache_ttl_secs,
             self.config.max_connections,
-            self.config.ip_whitelist.len()
+            self.ip_filter.load().whitelist_len(),
+            self.ip_filter.load().blacklist_len(),
+            self.dest_filter.len()
         );
 
+        if self.ip_rules.has_files() {
+            let poll = (self.config.ip_rules_poll_secs > 0)
+                .then(|| Duration::from_secs(self.config.ip_rules_poll_secs));
+            tokio::spawn(rule_reload::watch(self.ip_rules.clone(), self.ip_filter.clone(), poll));
+        }
+
         loop {
             let (socket, addr) = match self.listener.as_mut().unwrap().accept().await {
                 Ok((socket, addr)) => (socket, addr),
                 Err(e) => return Err(ServerError::ConnectionError(e.to_string())),
             };
 
-            // IP whitelist check
+            // IP whitelist/blacklist check
             let src_ip = addr.ip();
-            if !self.ip_filter.allows(&src_ip) {
-                log::warn!("Rejected connection from {}, not in whitelist", addr);
+            if !self.ip_filter.load().allows(&src_ip) {
+                log::warn!("Rejected connection from {} by source IP rules", addr);
                 // Drop immediately
                 continue;
             }
 
+            if let Some(geo) = &self.source_geo
+                && !geo.allows(&src_ip)
+            {
+                log::warn!("Rejected connection from {} by GeoIP rules", addr);
+                continue;
+            }
+
+            if let Some(lockout) = &self.lockout
+                && lockout.is_banned(&src_ip)
+            {
+                log::debug!("Rejected connection from {}, banned after failed logins", addr);
+                continue;
+            }
+
             // Acquire connection permit
             let permit = match self.conn_semaphore.clone().try_acquire_owned() {
                 Ok(p) => p,
@@ -88,16 +277,35 @@ impl SocksServer {
 
             let server_config = self.config.clone();
             let dns_cache = self.dns_cache.clone();
+            let dest_filter = self.dest_filter.clone();
+            let connector = self.connector.clone();
+            let authenticator = self.authenticator.clone();
             // Keep permit alive for the lifetime of the task
             tokio::spawn(async move {
                 let _permit = permit; // held until task ends
-                let mut  handler = ConnectionHandler::new(socket, addr, server_config, dns_cache);
-                if let Err(e) = handler.handle().await {
-                    log::error!("Error handling connection from {}: {}", addr, e);
 
-                    handler.close().await.unwrap_or_else(|e| {
-                        log::error!("Failed to close connection from {}: {}", addr, e);
-                    });
+                // HTTP proxy clients share the port; tell them apart by the first byte
+                let mut first_byte = [0u8; 1];
+                let is_http = matches!(socket.peek(&mut first_byte).await, Ok(1) if looks_like_http(first_byte[0]));
+
+                if is_http {
+                    let mut handler = HttpProxyHandler::new(socket, addr, dns_cache, dest_filter, connector, authenticator);
+                    if let Err(e) = handler.handle().await {
+                        log::error!("Error handling HTTP proxy connection from {}: {}", addr, e);
+
+                        handler.close().await.unwrap_or_else(|e| {
+                            log::error!("Failed to close connection from {}: {}", addr, e);
+                        });
+                    }
+                } else {
+                    let mut handler = ConnectionHandler::new(socket, addr, server_config, dns_cache, dest_filter, connector, authenticator);
+                    if let Err(e) = handler.handle().await {
+                        log::error!("Error handling connection from {}: {}", addr, e);
+
+                        handler.close().await.unwrap_or_else(|e| {
+                            log::error!("Failed to close connection from {}: {}", addr, e);
+                        });
+                    }
                 }
 
                 log::info!("Connection from {} closed", addr);
@@ -105,3 +313,35 @@ impl SocksServer {
         }
     }
 }
+
+/// The system resolver, unless upstream DNS servers are configured.
+fn build_resolver(config: &ServerConfig) -> std::result::Result<Arc<dyn Resolver>, String> {
+    let mut upstreams: Vec<Arc<dyn Upstream>> = Vec::new();
+    for server in &config.dns_servers {
+        upstreams.push(Arc::new(PlainUpstream::parse(server, config.dns_tcp)?));
+    }
+    if !config.dns_over_tls.is_empty() {
+        let tls = resolver_dot::tls_config(config.dns_tls_ca_file.as_deref())?;
+        for server in &config.dns_over_tls {
+            upstreams.push(Arc::new(DotUpstream::parse(server, tls.clone())?));
+        }
+    }
+    for url in &config.dns_over_https {
+        upstreams.push(Arc::new(DohUpstream::parse(url, config.dns_tls_ca_file.as_deref())?));
+    }
+    if upstreams.is_empty() {
+        return Ok(Arc::new(SystemResolver));
+    }
+
+    log::info!(
+        "Resolving through {}",
+        upstreams.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(", ")
+    );
+    let resolver = StubResolver::new(StubConfig {
+        upstreams,
+        timeout: Duration::from_millis(config.dns_timeout_ms),
+        attempts: config.dns_attempts,
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(Arc::new(resolver))
+}
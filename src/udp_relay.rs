@@ -0,0 +1,322 @@
+use crate::dest_filter::DestFilter;
+use crate::dns_cache::DnsCache;
+use crate::errors::{Result, ServerError};
+use crate::handlers::{encode_socket_addr, AddressType, TargetAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Parses the RFC 1928 UDP request header.
+///
+/// Returns the FRAG field, the destination and the offset at which the payload starts.
+pub fn parse_udp_header(buf: &[u8]) -> Result<(u8, TargetAddr, usize)> {
+    if buf.len() < 4 {
+        return Err(ServerError::InvalidRequestFormat);
+    }
+    let frag = buf[2];
+    let address_type =
+        AddressType::try_from(buf[3]).map_err(|_| ServerError::InvalidRequestFormat)?;
+
+    let (target, offset) = match address_type {
+        AddressType::IPv4 => {
+            let end = 4 + 4;
+            let ip: [u8; 4] = buf
+                .get(4..end)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(ServerError::InvalidRequestFormat)?;
+            let port = read_port(buf, end)?;
+            (TargetAddr::Ip(SocketAddr::from((Ipv4Addr::from(ip), port))), end + 2)
+        }
+        AddressType::DomainName => {
+            let length = *buf.get(4).ok_or(ServerError::InvalidRequestFormat)? as usize;
+            let end = 5 + length;
+            let domain = buf.get(5..end).ok_or(ServerError::InvalidRequestFormat)?;
+            let domain = String::from_utf8(domain.to_vec())
+                .map_err(|_| ServerError::InvalidRequestFormat)?;
+            let port = read_port(buf, end)?;
+            (TargetAddr::Domain(domain, port), end + 2)
+        }
+        AddressType::IPv6 => {
+            let end = 4 + 16;
+            let ip: [u8; 16] = buf
+                .get(4..end)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(ServerError::InvalidRequestFormat)?;
+            let port = read_port(buf, end)?;
+            (TargetAddr::Ip(SocketAddr::from((Ipv6Addr::from(ip), port))), end + 2)
+        }
+    };
+
+    Ok((frag, target, offset))
+}
+
+/// Wraps `payload` in a UDP request header whose DST fields carry `source`.
+pub fn encode_udp_datagram(source: &SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(22 + payload.len());
+    buf.extend_from_slice(&[0, 0, 0]);
+    encode_socket_addr(&mut buf, source);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn read_port(buf: &[u8], offset: usize) -> Result<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(ServerError::InvalidRequestFormat)
+}
+
+/// Relays datagrams for a single UDP ASSOCIATE request.
+pub struct UdpRelay {
+    socket: UdpSocket,
+    client_ip: IpAddr,
+    client_port: Option<u16>,
+    client_address: Option<SocketAddr>,
+    dns_cache: Arc<DnsCache>,
+    dest_filter: Arc<DestFilter>,
+    outbound_v4: Option<UdpSocket>,
+    outbound_v6: Option<UdpSocket>,
+}
+
+impl UdpRelay {
+    /// `client_port` of 0 means the port is learned from the first datagram.
+    pub fn new(socket: UdpSocket, client_ip: IpAddr, client_port: u16, dns_cache: Arc<DnsCache>, dest_filter: Arc<DestFilter>) -> Self {
+        UdpRelay {
+            socket,
+            client_ip: client_ip.to_canonical(),
+            client_port: (client_port != 0).then_some(client_port),
+            client_address: None,
+            dns_cache,
+            dest_filter,
+            outbound_v4: None,
+            outbound_v6: None,
+        }
+    }
+
+    /// Runs the relay until the control connection is closed by the client.
+    pub async fn run(mut self, control: &mut TcpStream) -> Result<()> {
+        let mut control_buf = [0u8; 64];
+        let mut client_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v4_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v6_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            tokio::select! {
+                res = control.read(&mut control_buf) => match res {
+                    Ok(0) | Err(_) => return Ok(()),
+                    Ok(_) => {}
+                },
+                // Socket errors (e.g. ICMP port unreachable reported by an
+                // earlier send) only lose one datagram: the association ends
+                // when the control connection closes
+                res = self.socket.recv_from(&mut client_buf) => match res {
+                    Ok((n, from)) => {
+                        if let Err(e) = self.forward_to_remote(&client_buf[..n], from).await {
+                            log::debug!("Dropped UDP datagram from {}: {}", from, e);
+                        }
+                    }
+                    Err(e) => log::debug!("UDP relay receive from client failed: {}", e),
+                },
+                res = recv_from(self.outbound_v4.as_ref(), &mut v4_buf) => {
+                    self.forward_reply(res.map(|(n, from)| (&v4_buf[..n], from))).await;
+                }
+                res = recv_from(self.outbound_v6.as_ref(), &mut v6_buf) => {
+                    self.forward_reply(res.map(|(n, from)| (&v6_buf[..n], from))).await;
+                }
+            }
+        }
+    }
+
+    async fn forward_to_remote(&mut self, datagram: &[u8], from: SocketAddr) -> Result<()> {
+        if from.ip().to_canonical() != self.client_ip {
+            return Err(ServerError::ConnectionError(format!(
+                "unexpected source {}",
+                from
+            )));
+        }
+        if self.client_port.is_some_and(|port| port != from.port()) {
+            return Err(ServerError::ConnectionError(format!(
+                "unexpected source port {}",
+                from.port()
+            )));
+        }
+        self.client_port = Some(from.port());
+        self.client_address = Some(from);
+
+        let (frag, target, offset) = parse_udp_header(datagram)?;
+        if frag != 0 {
+            // Fragmentation is optional in RFC 1928 and not supported here
+            return Err(ServerError::ConnectionError(format!(
+                "fragmented datagram (FRAG={})",
+                frag
+            )));
+        }
+
+        let (domain, addrs) = match &target {
+            TargetAddr::Ip(addr) => (None, vec![*addr]),
+            TargetAddr::Domain(domain, port) => {
+                if self.dest_filter.denies_domain(domain, *port) {
+                    return Err(ServerError::ConnectionNotAllowed(format!(
+                        "{} denied by destination rules",
+                        target
+                    )));
+                }
+                (Some(domain.as_str()), self.dns_cache.resolve(domain, *port).await?)
+            }
+        };
+        let destination = addrs
+            .into_iter()
+            .find(|a| self.dest_filter.allows(domain, a.ip(), a.port()))
+            .ok_or_else(|| {
+                ServerError::ConnectionNotAllowed(format!("{} denied by destination rules", target))
+            })?;
+
+        let outbound = self.outbound_socket(&destination).await?;
+        outbound.send_to(&datagram[offset..], destination).await?;
+        Ok(())
+    }
+
+    /// Relays a datagram received on an outbound socket, logging and
+    /// dropping it on errors.
+    async fn forward_reply(&self, received: std::io::Result<(&[u8], SocketAddr)>) {
+        match received {
+            Ok((payload, from)) => {
+                if let Err(e) = self.forward_to_client(payload, from).await {
+                    log::debug!("Dropped UDP datagram from {}: {}", from, e);
+                }
+            }
+            Err(e) => log::debug!("UDP relay receive from remote failed: {}", e),
+        }
+    }
+
+    async fn forward_to_client(&self, payload: &[u8], from: SocketAddr) -> Result<()> {
+        // Datagrams are only sent out after the client has been seen
+        let Some(client_address) = self.client_address else {
+            return Ok(());
+        };
+        let datagram = encode_udp_datagram(&from, payload);
+        self.socket.send_to(&datagram, client_address).await?;
+        Ok(())
+    }
+
+    async fn outbound_socket(&mut self, destination: &SocketAddr) -> Result<&UdpSocket> {
+        let (slot, bind_address) = match destination {
+            SocketAddr::V4(_) => (&mut self.outbound_v4, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+            SocketAddr::V6(_) => (&mut self.outbound_v6, SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))),
+        };
+        if slot.is_none() {
+            *slot = Some(UdpSocket::bind(bind_address).await?);
+        }
+        Ok(slot.as_ref().unwrap())
+    }
+}
+
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(atyp: u8, address: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, atyp];
+        buf.extend_from_slice(address);
+        buf
+    }
+
+    fn ip(target: &TargetAddr) -> SocketAddr {
+        match target {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(domain, port) => panic!("unexpected domain {}:{}", domain, port),
+        }
+    }
+
+    #[test]
+    fn rejects_short_headers() {
+        for len in 0..4 {
+            assert!(parse_udp_header(&[0, 0, 0, 1][..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_addresses_and_ports() {
+        let complete = [
+            header(1, &[192, 0, 2, 1, 0, 53]),
+            header(3, b"\x07example\x00\x35"),
+            header(4, &[[0x20, 0x01, 0x0d, 0xb8].as_slice(), &[0; 12], &[0, 53]].concat()),
+        ];
+        for datagram in &complete {
+            assert!(parse_udp_header(datagram).is_ok(), "{:?}", datagram);
+            // Every cut before the payload loses part of the address or port
+            for len in 4..datagram.len() {
+                assert!(parse_udp_header(&datagram[..len]).is_err(), "{:?}", &datagram[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_domains() {
+        let (_, target, offset) = parse_udp_header(b"\x00\x00\x00\x03\x07example\x00\x35data").unwrap();
+        assert!(matches!(&target, TargetAddr::Domain(domain, 53) if domain == "example"));
+        assert_eq!(offset, 14);
+
+        let (_, target, offset) = parse_udp_header(&header(3, &[0, 0, 53])).unwrap();
+        assert!(matches!(&target, TargetAddr::Domain(domain, 53) if domain.is_empty()));
+        assert_eq!(offset, 7);
+
+        assert!(parse_udp_header(&header(3, &[2, 0xc3, 0x28, 0, 53])).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_address_types() {
+        for atyp in [0, 2, 5, 0xff] {
+            assert!(parse_udp_header(&header(atyp, &[192, 0, 2, 1, 0, 53])).is_err(), "{}", atyp);
+        }
+    }
+
+    #[test]
+    fn returns_the_frag_field() {
+        let mut datagram = header(1, &[192, 0, 2, 1, 0, 53]);
+        datagram[2] = 3;
+        let (frag, _, _) = parse_udp_header(&datagram).unwrap();
+        assert_eq!(frag, 3);
+    }
+
+    #[test]
+    fn encoded_datagrams_parse_back() {
+        for source in ["192.0.2.1:5353", "[2001:db8::1]:443", "[::ffff:192.0.2.1]:80"] {
+            let source: SocketAddr = source.parse().unwrap();
+            let datagram = encode_udp_datagram(&source, b"payload");
+            let (frag, target, offset) = parse_udp_header(&datagram).unwrap();
+            assert_eq!(frag, 0);
+            let parsed = ip(&target);
+            assert_eq!(parsed.ip(), source.ip().to_canonical());
+            assert_eq!(parsed.port(), source.port());
+            assert_eq!(&datagram[offset..], b"payload");
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_fragmented_datagrams() {
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut relay = UdpRelay::new(
+            relay_socket,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            0,
+            Arc::new(DnsCache::new_default()),
+            Arc::new(DestFilter::default()),
+        );
+        let mut datagram = encode_udp_datagram(&"192.0.2.1:53".parse().unwrap(), b"x");
+        datagram[2] = 1;
+        let from = "127.0.0.1:40000".parse().unwrap();
+        let err = relay.forward_to_remote(&datagram, from).await.unwrap_err();
+        assert!(err.to_string().contains("FRAG=1"), "{}", err);
+        assert!(relay.outbound_v4.is_none());
+    }
+}
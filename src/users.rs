@@ -0,0 +1,258 @@
+use argon2::{CustomizedPasswordHasher, PasswordHash};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+/// Cost of the bcrypt hash made for the `--username`/`--password` account.
+const PASSWORD_BCRYPT_COST: u32 = 10;
+
+/// Password and salt of the dummy hash checked for unknown usernames.
+const DUMMY_PASSWORD: &str = "rusk-socks5";
+const DUMMY_SALT: &[u8] = b"rusk-socks5-salt";
+
+/// Stored secret for one account.
+#[derive(Debug, Clone)]
+enum Credential {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Credential {
+    fn from_hash(hash: &str) -> Result<Self, String> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p)) {
+            Ok(Credential::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok() {
+            Ok(Credential::Argon2(hash.to_string()))
+        } else {
+            Err("unsupported password hash, expected bcrypt ($2y$) or argon2 ($argon2id$)".to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Credential::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Credential::Argon2(hash) => {
+                use argon2::PasswordVerifier;
+                argon2::Argon2::default()
+                    .verify_password(password.as_bytes(), hash.as_str())
+                    .is_ok()
+            }
+        }
+    }
+
+    /// The part of the hash that decides how long a check takes: the scheme
+    /// and cost for bcrypt (`$2y$05`), everything before the salt for argon2
+    /// (`$argon2id$v=19$m=19456,t=2,p=1`).
+    fn cost(&self) -> &str {
+        match self {
+            Credential::Bcrypt(hash) => hash.get(..6).unwrap_or(hash),
+            Credential::Argon2(hash) => hash.rsplitn(3, '$').nth(2).unwrap_or(hash),
+        }
+    }
+
+    /// A hash of a password nobody knows, with the same scheme and cost.
+    fn dummy(&self) -> Credential {
+        match self {
+            Credential::Bcrypt(hash) => {
+                let cost = hash.get(4..6).and_then(|c| c.parse().ok()).unwrap_or(PASSWORD_BCRYPT_COST);
+                Credential::Bcrypt(bcrypt::hash(DUMMY_PASSWORD, cost).unwrap_or_default())
+            }
+            Credential::Argon2(hash) => {
+                let dummy = PasswordHash::new(hash).ok().and_then(|parsed| {
+                    let params = argon2::Params::try_from(&parsed).ok()?;
+                    argon2::Argon2::default()
+                        .hash_password_customized(
+                            DUMMY_PASSWORD.as_bytes(),
+                            DUMMY_SALT,
+                            Some(parsed.algorithm.as_str()),
+                            parsed.version,
+                            params,
+                        )
+                        .ok()
+                });
+                Credential::Argon2(dummy.map(|d| d.to_string()).unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Username/password accounts, loaded from an htpasswd-style users file.
+#[derive(Debug, Clone, Default)]
+pub struct UserStore {
+    users: HashMap<String, Credential>,
+    /// Checked for unknown usernames; built on first use from the most
+    /// common scheme and cost among the accounts.
+    dummy: Arc<OnceLock<Credential>>,
+}
+
+impl UserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read users file {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Parses `username:hash` lines as written by `htpasswd -B`. Blank lines
+    /// and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut store = Self::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected username:hash", index + 1))?;
+            let credential =
+                Credential::from_hash(hash).map_err(|e| format!("line {}: {}", index + 1, e))?;
+            store.insert(username, credential);
+        }
+        Ok(store)
+    }
+
+    /// Adds an account for a password given on the command line. The password
+    /// is bcrypt-hashed here so it is checked like the users file entries.
+    pub fn add_password(&mut self, username: &str, password: &str) -> Result<(), String> {
+        let hash = bcrypt::hash(password, PASSWORD_BCRYPT_COST)
+            .map_err(|e| format!("Failed to hash the password of {}: {}", username, e))?;
+        self.insert(username, Credential::Bcrypt(hash));
+        Ok(())
+    }
+
+    fn insert(&mut self, username: &str, credential: Credential) {
+        self.users.insert(username.to_string(), credential);
+        self.dummy = Arc::new(OnceLock::new());
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Checks a username/password pair. Hashing runs on the blocking pool, and
+    /// unknown usernames are checked against a dummy hash of the same scheme
+    /// and cost as the accounts, so they cannot be told apart by timing.
+    pub async fn verify(&self, username: &str, password: &str) -> bool {
+        let credential = self.users.get(username).cloned();
+        let template = match (&credential, self.dummy.get()) {
+            (None, None) => Some(self.most_common()),
+            _ => None,
+        };
+        let dummy = self.dummy.clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || match credential {
+            Some(credential) => credential.verify(&password),
+            None => {
+                let dummy = dummy.get_or_init(|| template.unwrap_or_else(default_credential).dummy());
+                let _ = dummy.verify(&password);
+                false
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// An account with the scheme and cost most accounts use.
+    fn most_common(&self) -> Credential {
+        let mut counts: HashMap<&str, (usize, &Credential)> = HashMap::new();
+        for credential in self.users.values() {
+            counts.entry(credential.cost()).or_insert((0, credential)).0 += 1;
+        }
+        counts
+            .iter()
+            .max_by_key(|(cost, (count, _))| (*count, *cost))
+            .map(|(_, (_, credential))| (*credential).clone())
+            .unwrap_or_else(default_credential)
+    }
+}
+
+/// Stands in for the accounts of an empty store: bcrypt at the cost used for
+/// `--password`.
+fn default_credential() -> Credential {
+    Credential::Bcrypt(format!("$2b${:02}$", PASSWORD_BCRYPT_COST))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bcrypt_hash(password: &str) -> String {
+        bcrypt::hash(password, 4).unwrap()
+    }
+
+    fn argon2_hash(password: &str) -> String {
+        let params = argon2::Params::new(64, 1, 1, None).unwrap();
+        argon2::Argon2::default()
+            .hash_password_customized(password.as_bytes(), b"0123456789abcdef", Some("argon2id"), None, params)
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn checks_bcrypt_and_argon2_entries() {
+        let contents = format!("alice:{}\nbob:{}\n", bcrypt_hash("alice-pw"), argon2_hash("bob-pw"));
+        let store = UserStore::parse(&contents).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.verify("alice", "alice-pw").await);
+        assert!(!store.verify("alice", "bob-pw").await);
+        assert!(store.verify("bob", "bob-pw").await);
+        assert!(!store.verify("bob", "alice-pw").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_users() {
+        let store = UserStore::parse(&format!("alice:{}", bcrypt_hash("alice-pw"))).unwrap();
+        assert!(!store.verify("carol", "alice-pw").await);
+        // Not even with the dummy hash's own password
+        assert!(!store.verify("carol", DUMMY_PASSWORD).await);
+        assert!(!UserStore::new().verify("carol", DUMMY_PASSWORD).await);
+    }
+
+    #[tokio::test]
+    async fn skips_comments_and_blank_lines() {
+        let contents = format!("# accounts\n\n  # indented comment\nalice:{}\n\n", bcrypt_hash("alice-pw"));
+        let store = UserStore::parse(&contents).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.verify("alice", "alice-pw").await);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let hash = bcrypt_hash("alice-pw");
+        let err = UserStore::parse(&format!("# header\nalice:{}\nbob\n", hash)).unwrap_err();
+        assert_eq!(err, "line 3: expected username:hash");
+        let err = UserStore::parse("alice:secret").unwrap_err();
+        assert!(err.starts_with("line 1: unsupported password hash"), "{}", err);
+        assert!(UserStore::parse("alice:$argon2id$garbage").is_err());
+    }
+
+    #[tokio::test]
+    async fn hashes_command_line_passwords() {
+        let mut store = UserStore::new();
+        store.add_password("alice", "alice-pw").unwrap();
+        assert!(matches!(&store.users["alice"], Credential::Bcrypt(hash) if hash.starts_with("$2b$10$")));
+        assert!(store.verify("alice", "alice-pw").await);
+        assert!(!store.verify("alice", "wrong").await);
+    }
+
+    #[test]
+    fn dummy_matches_the_accounts_scheme_and_cost() {
+        let store = UserStore::parse(&format!("a:{}\nb:{}", bcrypt_hash("a"), bcrypt_hash("b"))).unwrap();
+        assert_eq!(store.most_common().dummy().cost(), "$2b$04");
+
+        let argon2 = argon2_hash("b");
+        let store = UserStore::parse(&format!("a:{}\nb:{}\nc:{}", bcrypt_hash("a"), argon2, argon2_hash("c"))).unwrap();
+        let dummy = store.most_common().dummy();
+        assert!(matches!(dummy, Credential::Argon2(_)));
+        assert_eq!(dummy.cost(), "$argon2id$v=19$m=64,t=1,p=1");
+
+        assert_eq!(UserStore::new().most_common().dummy().cost(), "$2b$10");
+    }
+}
@@ -0,0 +1,248 @@
+use rusk_socks5::auth::{Authenticator, Identity};
+use rusk_socks5::auth_ldap::{LdapAuthenticator, LdapConfig};
+use rusk_socks5::errors::ServerError;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const SERVICE_DN: &str = "cn=svc,dc=test";
+const PEOPLE: &str = "ou=people,dc=test";
+const GROUP: &str = "cn=proxy,ou=groups,dc=test";
+
+/// Accounts of the stub directory: (uid, password, member of GROUP).
+const USERS: &[(&str, &str, bool)] = &[("alice", "alice-pw", true), ("bob", "bob-pw", false)];
+
+const INVALID_CREDENTIALS: u8 = 49;
+
+/// Encodes a BER element with a definite length.
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len @ 0..0x80 => out.push(len as u8),
+        len @ 0x80..0x100 => out.extend_from_slice(&[0x81, len as u8]),
+        len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Splits the first BER element off `buf`: (tag, content, rest).
+fn split_tlv(buf: &[u8]) -> (u8, &[u8], &[u8]) {
+    let (len, header) = match buf[1] {
+        len if len < 0x80 => (len as usize, 2),
+        0x81 => (buf[2] as usize, 3),
+        _ => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+    };
+    (buf[0], &buf[header..header + len], &buf[header + len..])
+}
+
+async fn read_tlv<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let tag = reader.read_u8().await?;
+    let first = reader.read_u8().await?;
+    let len = match first {
+        len if len < 0x80 => len as usize,
+        0x81 => reader.read_u8().await? as usize,
+        _ => reader.read_u16().await? as usize,
+    };
+    let mut content = vec![0u8; len];
+    reader.read_exact(&mut content).await?;
+    Ok(tlv(tag, &content))
+}
+
+fn message(id: &[u8], op: Vec<u8>) -> Vec<u8> {
+    tlv(0x30, &[tlv(0x02, id), op].concat())
+}
+
+/// An LDAPResult with `code` inside a protocol op tagged `tag`.
+fn result(tag: u8, code: u8) -> Vec<u8> {
+    tlv(tag, &[tlv(0x0a, &[code]), tlv(0x04, b""), tlv(0x04, b"")].concat())
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+}
+
+/// A directory server answering simple binds and searches for [`USERS`].
+/// Filters are not parsed: a search matches the users whose uid appears in
+/// the filter. Every operation is logged as "bind DN" or "search BASE".
+async fn directory() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ldap://{}", listener.local_addr().unwrap());
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let server_log = log.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let log = server_log.clone();
+            tokio::spawn(async move {
+                while let Ok(request) = read_tlv(&mut stream).await {
+                    let (_, body, _) = split_tlv(&request);
+                    let (_, id, rest) = split_tlv(body);
+                    let (op, fields, _) = split_tlv(rest);
+                    let mut replies = Vec::new();
+                    match op {
+                        // BindRequest
+                        0x60 => {
+                            let (_, _version, rest) = split_tlv(fields);
+                            let (_, dn, rest) = split_tlv(rest);
+                            let (_, password, _) = split_tlv(rest);
+                            let dn = String::from_utf8_lossy(dn).to_string();
+                            let password = String::from_utf8_lossy(password).to_string();
+                            log.lock().unwrap().push(format!("bind {}", dn));
+                            let valid = (dn.is_empty() && password.is_empty())
+                                || (dn == SERVICE_DN && password == "svc-pw")
+                                || USERS
+                                    .iter()
+                                    .any(|(uid, pw, _)| dn == format!("uid={},{}", uid, PEOPLE) && password == *pw);
+                            let code = if valid { 0 } else { INVALID_CREDENTIALS };
+                            replies.push(message(id, result(0x61, code)));
+                        }
+                        // SearchRequest
+                        0x63 => {
+                            let (_, base, filter) = split_tlv(fields);
+                            let base = String::from_utf8_lossy(base).to_string();
+                            log.lock().unwrap().push(format!("search {}", base));
+                            let matches: Vec<String> = if base == PEOPLE {
+                                let uids: Vec<&str> = if contains(filter, "duplicate") {
+                                    vec!["alice", "bob"]
+                                } else {
+                                    USERS.iter().map(|u| u.0).filter(|uid| contains(filter, uid)).collect()
+                                };
+                                uids.iter().map(|uid| format!("uid={},{}", uid, PEOPLE)).collect()
+                            } else if base == GROUP
+                                && USERS.iter().any(|(uid, _, member)| *member && contains(filter, uid))
+                            {
+                                vec![GROUP.to_string()]
+                            } else {
+                                Vec::new()
+                            };
+                            for dn in matches {
+                                let entry = tlv(0x64, &[tlv(0x04, dn.as_bytes()), tlv(0x30, b"")].concat());
+                                replies.push(message(id, entry));
+                            }
+                            replies.push(message(id, result(0x65, 0)));
+                        }
+                        // UnbindRequest
+                        _ => return,
+                    }
+                    for reply in replies {
+                        if stream.write_all(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (url, log)
+}
+
+fn config(url: String) -> LdapConfig {
+    LdapConfig {
+        url,
+        bind_dn: Some(SERVICE_DN.to_string()),
+        bind_password: Some("svc-pw".to_string()),
+        base_dn: PEOPLE.to_string(),
+        user_filter: "(uid={username})".to_string(),
+        required_group: None,
+        pool_size: 2,
+        timeout: Duration::from_secs(2),
+        cache_ttl: Duration::from_secs(60),
+        negative_cache_ttl: Duration::from_secs(60),
+    }
+}
+
+fn client() -> SocketAddr {
+    "192.0.2.10:40000".parse().unwrap()
+}
+
+fn assert_rejected(result: rusk_socks5::errors::Result<Identity>) {
+    match result {
+        Err(ServerError::AuthenticationFailed(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn searches_then_binds_as_the_user() {
+    let (url, log) = directory().await;
+    let auth = LdapAuthenticator::new(config(url));
+
+    assert_eq!(auth.authenticate(client(), "alice", "alice-pw").await.unwrap(), Identity::user("alice"));
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            format!("bind {}", SERVICE_DN),
+            format!("search {}", PEOPLE),
+            format!("bind uid=alice,{}", PEOPLE),
+            format!("bind {}", SERVICE_DN),
+        ]
+    );
+
+    // Cached, and the pooled connection is reused for the next user
+    assert!(auth.authenticate(client(), "alice", "alice-pw").await.is_ok());
+    assert!(auth.authenticate(client(), "bob", "bob-pw").await.is_ok());
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 7);
+    assert_eq!(log.iter().filter(|op| **op == format!("bind {}", SERVICE_DN)).count(), 3);
+}
+
+#[tokio::test]
+async fn rejects_wrong_passwords_and_unknown_users() {
+    let (url, log) = directory().await;
+    let auth = LdapAuthenticator::new(config(url));
+
+    assert_rejected(auth.authenticate(client(), "alice", "bob-pw").await);
+    // The connection is bound back to the service account
+    assert_eq!(log.lock().unwrap().last().unwrap(), &format!("bind {}", SERVICE_DN));
+    assert_rejected(auth.authenticate(client(), "carol", "carol-pw").await);
+    assert_rejected(auth.authenticate(client(), "duplicate", "alice-pw").await);
+}
+
+#[tokio::test]
+async fn empty_password_never_reaches_the_directory() {
+    let (url, log) = directory().await;
+    let auth = LdapAuthenticator::new(config(url));
+    assert_rejected(auth.authenticate(client(), "alice", "").await);
+    assert!(log.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn checks_group_membership() {
+    let (url, log) = directory().await;
+    let auth = LdapAuthenticator::new(LdapConfig {
+        required_group: Some(GROUP.to_string()),
+        ..config(url)
+    });
+
+    assert!(auth.authenticate(client(), "alice", "alice-pw").await.is_ok());
+    assert_eq!(log.lock().unwrap().last().unwrap(), &format!("search {}", GROUP));
+    // Right password, but not a member
+    assert_rejected(auth.authenticate(client(), "bob", "bob-pw").await);
+}
+
+#[tokio::test]
+async fn outages_are_not_rejections() {
+    let (url, _) = directory().await;
+    let auth = LdapAuthenticator::new(LdapConfig {
+        bind_password: Some("wrong".to_string()),
+        ..config(url)
+    });
+    // A service account the directory refuses is a configuration problem,
+    // not a wrong user password
+    match auth.authenticate(client(), "alice", "alice-pw").await {
+        Err(ServerError::AuthBackendUnavailable(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ldap://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let auth = LdapAuthenticator::new(config(url));
+    match auth.authenticate(client(), "alice", "alice-pw").await {
+        Err(ServerError::AuthBackendUnavailable(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
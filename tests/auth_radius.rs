@@ -0,0 +1,245 @@
+use hmac::{Hmac, KeyInit, Mac};
+use md5::{Digest, Md5};
+use rusk_socks5::auth::{Authenticator, Identity};
+use rusk_socks5::auth_radius::{RadiusAuthenticator, RadiusConfig};
+use rusk_socks5::errors::{Result, ServerError};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const SECRET: &[u8] = b"s3cret";
+
+/// Accounts of the stub server; bob's password spans several blocks.
+const USERS: &[(&str, &str)] = &[("alice", "right"), ("bob", "a passphrase longer than thirty-two bytes")];
+
+#[derive(Clone, Copy)]
+enum Behavior {
+    /// Accepts the passwords in [`USERS`] and rejects anything else.
+    Check(Signature),
+    /// Answers Access-Accept with a Response Authenticator that does not
+    /// verify.
+    Forge,
+    Silent,
+}
+
+/// The Message-Authenticator attached to answers.
+#[derive(Clone, Copy)]
+enum Signature {
+    Valid,
+    /// No Message-Authenticator, as in a BlastRADIUS forgery.
+    Missing,
+    /// Computed with another secret.
+    WrongSecret,
+}
+
+const CHECK: Behavior = Behavior::Check(Signature::Valid);
+
+/// A RADIUS server on 127.0.0.1, returning its address and a request count.
+async fn radius_server(behavior: Behavior) -> (String, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let request = &buf[..n];
+            let (code, signature) = match behavior {
+                Behavior::Silent => continue,
+                Behavior::Forge => (2, Signature::Valid),
+                Behavior::Check(signature) => {
+                    let (username, password) = credentials(request);
+                    if USERS.iter().any(|(u, p)| u.as_bytes() == username && p.as_bytes() == password) {
+                        (2, signature)
+                    } else {
+                        (3, signature)
+                    }
+                }
+            };
+            let mut response = vec![code, request[1], 0, 20];
+            response.extend_from_slice(&request[4..20]);
+            let secret = match signature {
+                Signature::Valid => Some(SECRET),
+                Signature::Missing => None,
+                Signature::WrongSecret => Some(b"other".as_slice()),
+            };
+            if let Some(secret) = secret {
+                sign(&mut response, secret);
+            }
+            let mut hasher = Md5::new();
+            hasher.update(&response);
+            hasher.update(SECRET);
+            let authenticator = hasher.finalize();
+            response[4..20].copy_from_slice(&authenticator);
+            if let Behavior::Forge = behavior {
+                response[4] ^= 0xff;
+            }
+            socket.send_to(&response, peer).await.unwrap();
+        }
+    });
+    (addr, requests)
+}
+
+/// Appends a Message-Authenticator (RFC 3579) to a response that still
+/// holds the Request Authenticator.
+fn sign(response: &mut Vec<u8>, secret: &[u8]) {
+    let offset = response.len() + 2;
+    response.extend_from_slice(&[80, 18]);
+    response.extend_from_slice(&[0; 16]);
+    let length = response.len() as u16;
+    response[2..4].copy_from_slice(&length.to_be_bytes());
+    let mut mac = <Hmac<Md5> as KeyInit>::new_from_slice(secret).unwrap();
+    mac.update(response);
+    response[offset..].copy_from_slice(&mac.finalize().into_bytes());
+}
+
+/// User-Name and the revealed User-Password (RFC 2865 section 5.2) of an
+/// Access-Request.
+fn credentials(request: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let authenticator = &request[4..20];
+    let (mut username, mut password) = (Vec::new(), Vec::new());
+    let mut attributes = &request[20..];
+    while attributes.len() >= 2 {
+        let (kind, len) = (attributes[0], attributes[1] as usize);
+        let value = &attributes[2..len];
+        match kind {
+            1 => username = value.to_vec(),
+            2 => {
+                let mut previous = authenticator.to_vec();
+                for block in value.chunks(16) {
+                    let mut hasher = Md5::new();
+                    hasher.update(SECRET);
+                    hasher.update(&previous);
+                    let key = hasher.finalize();
+                    password.extend(block.iter().zip(key.iter()).map(|(c, k)| c ^ k));
+                    previous = block.to_vec();
+                }
+                while password.last() == Some(&0) {
+                    password.pop();
+                }
+            }
+            _ => {}
+        }
+        attributes = &attributes[len..];
+    }
+    (username, password)
+}
+
+fn authenticator(servers: Vec<String>) -> RadiusAuthenticator {
+    RadiusAuthenticator::new(RadiusConfig {
+        servers,
+        secret: String::from_utf8(SECRET.to_vec()).unwrap(),
+        timeout: Duration::from_millis(100),
+        retries: 1,
+        nas_identifier: "rusk-socks5".to_string(),
+    })
+    .unwrap()
+}
+
+fn client() -> SocketAddr {
+    "192.0.2.10:40000".parse().unwrap()
+}
+
+fn assert_rejected(result: Result<Identity>) {
+    match result {
+        Err(ServerError::AuthenticationFailed(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+fn assert_unavailable(result: Result<Identity>) {
+    match result {
+        Err(ServerError::AuthBackendUnavailable(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn access_accept_lets_the_user_in() {
+    let (server, requests) = radius_server(CHECK).await;
+    let auth = authenticator(vec![server]);
+    for (username, password) in USERS {
+        assert_eq!(auth.authenticate(client(), username, password).await.unwrap(), Identity::user(username));
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn access_reject_is_a_failed_login() {
+    let (server, _) = radius_server(CHECK).await;
+    let auth = authenticator(vec![server]);
+    assert_rejected(auth.authenticate(client(), "alice", "wrong").await);
+    // Only a prefix of the real password
+    assert_rejected(auth.authenticate(client(), "bob", "a passphrase longer than thirty").await);
+}
+
+#[tokio::test]
+async fn forged_responses_are_ignored() {
+    let (server, requests) = radius_server(Behavior::Forge).await;
+    let auth = authenticator(vec![server]);
+    assert_unavailable(auth.authenticate(client(), "alice", "wrong").await);
+    // The forged answers were dropped and the request retransmitted
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn responses_without_a_valid_message_authenticator_are_ignored() {
+    for signature in [Signature::Missing, Signature::WrongSecret] {
+        let (server, requests) = radius_server(Behavior::Check(signature)).await;
+        let auth = authenticator(vec![server]);
+        // Neither the accept nor the reject is believed
+        assert_unavailable(auth.authenticate(client(), "alice", "right").await);
+        assert_unavailable(auth.authenticate(client(), "alice", "wrong").await);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+}
+
+#[tokio::test]
+async fn fails_over_to_the_next_server() {
+    let (silent, silent_requests) = radius_server(Behavior::Silent).await;
+    let (good, good_requests) = radius_server(CHECK).await;
+    let auth = authenticator(vec![silent, good]);
+
+    assert!(auth.authenticate(client(), "alice", "right").await.is_ok());
+    assert_eq!(silent_requests.load(Ordering::SeqCst), 2);
+    assert_eq!(good_requests.load(Ordering::SeqCst), 1);
+
+    // The server that answered is tried first from now on
+    assert_rejected(auth.authenticate(client(), "alice", "wrong").await);
+    assert_eq!(silent_requests.load(Ordering::SeqCst), 2);
+    assert_eq!(good_requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn no_answer_is_an_outage() {
+    let (first, _) = radius_server(Behavior::Silent).await;
+    let (second, _) = radius_server(Behavior::Silent).await;
+    let auth = authenticator(vec![first, second]);
+    assert_unavailable(auth.authenticate(client(), "alice", "right").await);
+}
+
+#[tokio::test]
+async fn rejects_passwords_over_128_bytes() {
+    let (server, requests) = radius_server(CHECK).await;
+    let auth = authenticator(vec![server]);
+    let password = "x".repeat(129);
+    assert_rejected(auth.authenticate(client(), "alice", &password).await);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn needs_a_server_and_a_secret() {
+    let config = |servers: Vec<String>, secret: &str| RadiusConfig {
+        servers,
+        secret: secret.to_string(),
+        timeout: Duration::from_millis(100),
+        retries: 1,
+        nas_identifier: "rusk-socks5".to_string(),
+    };
+    assert!(RadiusAuthenticator::new(config(Vec::new(), "s3cret")).is_err());
+    assert!(RadiusAuthenticator::new(config(vec!["127.0.0.1".to_string()], "")).is_err());
+}
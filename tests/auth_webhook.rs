@@ -0,0 +1,152 @@
+use rusk_socks5::auth::{Authenticator, Identity};
+use rusk_socks5::auth_webhook::{WebhookAuthenticator, WebhookConfig};
+use rusk_socks5::errors::ServerError;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// An HTTP endpoint answering every request with `status` and `body` after
+/// `delay`, recording the request bodies.
+async fn webhook(status: u16, body: &'static str, delay: Duration) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/auth", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    let mut content_length = 0;
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    loop {
+                        line.clear();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line.trim_end().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':')
+                            && name.eq_ignore_ascii_case("content-length")
+                        {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    let mut request = vec![0u8; content_length];
+                    stream.read_exact(&mut request).await.unwrap();
+                    seen.lock().unwrap().push(serde_json::from_slice(&request).unwrap());
+
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (url, requests)
+}
+
+fn authenticator(url: String, fail_open: bool) -> WebhookAuthenticator {
+    WebhookAuthenticator::new(WebhookConfig {
+        url,
+        timeout: Duration::from_millis(200),
+        cache_ttl: Duration::from_secs(60),
+        negative_cache_ttl: Duration::from_secs(60),
+        fail_open,
+    })
+    .unwrap()
+}
+
+fn client() -> SocketAddr {
+    "192.0.2.10:40000".parse().unwrap()
+}
+
+#[tokio::test]
+async fn allowed_login_is_cached() {
+    let (url, requests) = webhook(200, r#"{"allow": true, "identity": "alice@example.com"}"#, Duration::ZERO).await;
+    let auth = authenticator(url, false);
+
+    let identity = auth.authenticate(client(), "alice", "secret").await.unwrap();
+    assert_eq!(identity, Identity::user("alice@example.com"));
+    assert_eq!(
+        requests.lock().unwrap()[0],
+        serde_json::json!({"username": "alice", "password": "secret", "client_ip": "192.0.2.10"})
+    );
+
+    let identity = auth.authenticate(client(), "alice", "secret").await.unwrap();
+    assert_eq!(identity, Identity::user("alice@example.com"));
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn denied_login_is_cached() {
+    let (url, requests) = webhook(200, r#"{"allow": false}"#, Duration::ZERO).await;
+    let auth = authenticator(url, true);
+
+    for _ in 0..2 {
+        match auth.authenticate(client(), "alice", "wrong").await {
+            Err(ServerError::AuthenticationFailed(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn forbidden_status_is_a_rejection() {
+    let (url, _) = webhook(403, "{}", Duration::ZERO).await;
+    // Not an outage, so fail-open does not apply
+    let auth = authenticator(url, true);
+    match auth.authenticate(client(), "alice", "wrong").await {
+        Err(ServerError::AuthenticationFailed(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn timeout_fails_closed_and_is_not_cached() {
+    let (url, requests) = webhook(200, r#"{"allow": true}"#, Duration::from_secs(1)).await;
+    let auth = authenticator(url, false);
+
+    for _ in 0..2 {
+        match auth.authenticate(client(), "alice", "secret").await {
+            Err(ServerError::AuthBackendUnavailable(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    assert_eq!(requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn server_error_fails_closed() {
+    let (url, _) = webhook(500, "", Duration::ZERO).await;
+    let auth = authenticator(url, false);
+    match auth.authenticate(client(), "alice", "secret").await {
+        Err(ServerError::AuthBackendUnavailable(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn fail_open_lets_users_in_during_outages() {
+    let (url, _) = webhook(500, "", Duration::ZERO).await;
+    let auth = authenticator(url, true);
+    assert_eq!(auth.authenticate(client(), "alice", "secret").await.unwrap(), Identity::user("alice"));
+
+    let (url, _) = webhook(200, r#"{"allow": true}"#, Duration::from_secs(1)).await;
+    let auth = authenticator(url, true);
+    assert_eq!(auth.authenticate(client(), "alice", "secret").await.unwrap(), Identity::user("alice"));
+}
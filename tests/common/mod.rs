@@ -0,0 +1,23 @@
+use std::net::Ipv4Addr;
+
+/// Flags of a successful recursive answer (QR, RD, RA).
+pub const NOERROR: u16 = 0x8180;
+
+/// A response to `query` with the given flags. A successful answer to an A
+/// query holds one record for `address` with TTL 60; everything else has
+/// no records.
+pub fn answer(query: &[u8], flags: u16, address: Ipv4Addr) -> Vec<u8> {
+    let question = &query[12..];
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let ancount = u8::from(flags == NOERROR && qtype == 1);
+    let mut buf = query[..2].to_vec();
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&[0, 1, 0, ancount, 0, 0, 0, 0]);
+    buf.extend_from_slice(question);
+    if ancount == 1 {
+        // Named by a pointer to the question
+        buf.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        buf.extend_from_slice(&address.octets());
+    }
+    buf
+}
@@ -0,0 +1,82 @@
+use rusk_socks5::connector::{Connector, ConnectorConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// A listener that never accepts and whose backlog is full, so further
+/// connection attempts to it hang instead of being refused.
+async fn black_hole() -> (TcpListener, Vec<TcpStream>) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut backlog = Vec::new();
+    for _ in 0..8 {
+        match tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await {
+            Ok(stream) => backlog.push(stream.unwrap()),
+            Err(_) => return (listener, backlog),
+        }
+    }
+    panic!("listen backlog never filled up");
+}
+
+fn connector(attempt_delay_ms: u64, timeout_ms: u64) -> Connector {
+    Connector::new(ConnectorConfig {
+        attempt_delay: Duration::from_millis(attempt_delay_ms),
+        timeout: Duration::from_millis(timeout_ms),
+    })
+}
+
+#[tokio::test]
+async fn black_holed_address_costs_one_attempt_delay() {
+    let (hole, _backlog) = black_hole().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs: Vec<SocketAddr> = vec![hole.local_addr().unwrap(), listener.local_addr().unwrap()];
+
+    let start = Instant::now();
+    let stream = connector(250, 10_000).connect(&addrs).await.unwrap();
+    let elapsed = start.elapsed();
+
+    assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+    assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, stream.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn later_addresses_are_not_tried_after_a_success() {
+    let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs = vec![first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+    let stream = connector(250, 10_000).connect(&addrs).await.unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addrs[0]);
+    let pending = tokio::time::timeout(Duration::from_millis(400), second.accept()).await;
+    assert!(pending.is_err(), "second address was connected to");
+}
+
+#[tokio::test]
+async fn unreachable_destination_times_out() {
+    let (hole, _backlog) = black_hole().await;
+    let addrs = vec![hole.local_addr().unwrap()];
+
+    let start = Instant::now();
+    let err = connector(100, 500).connect(&addrs).await.unwrap_err();
+    let elapsed = start.elapsed();
+
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn refused_addresses_report_the_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let err = connector(250, 10_000).connect(&[addr]).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
@@ -0,0 +1,176 @@
+use async_trait::async_trait;
+use rusk_socks5::dns_cache::{DnsCache, DnsCacheConfig};
+use rusk_socks5::errors::{Result, ServerError};
+use rusk_socks5::resolver::{Lookup, Resolver};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[derive(Clone, Copy)]
+enum Outcome {
+    /// One address with the resolver's TTL.
+    Found,
+    /// The name does not exist.
+    NxDomain,
+    /// The lookup fails.
+    Fail,
+}
+
+/// Counts lookups and answers slowly, so that concurrent misses overlap.
+struct CountingResolver {
+    calls: AtomicUsize,
+    outcome: Outcome,
+    ttl: Duration,
+}
+
+impl CountingResolver {
+    fn new(outcome: Outcome, ttl: Duration) -> Arc<Self> {
+        Arc::new(CountingResolver {
+            calls: AtomicUsize::new(0),
+            outcome,
+            ttl,
+        })
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Resolver for CountingResolver {
+    async fn lookup(&self, host: &str) -> Result<Lookup> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let addrs = match self.outcome {
+            Outcome::Found => vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))],
+            Outcome::NxDomain => Vec::new(),
+            Outcome::Fail => return Err(ServerError::HostUnreachable(format!("{}: timed out", host))),
+        };
+        Ok(Lookup {
+            addrs,
+            ttl: Some(self.ttl),
+        })
+    }
+}
+
+async fn resolve_concurrently(outcome: Outcome) -> (usize, Vec<Result<Vec<std::net::SocketAddr>>>) {
+    let resolver = CountingResolver::new(outcome, Duration::from_secs(60));
+    let cache = Arc::new(DnsCache::with_config(DnsCacheConfig::default(), resolver.clone()));
+    let tasks: Vec<_> = (0..200)
+        .map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.resolve("Example.COM", 443).await })
+        })
+        .collect();
+    let mut results = Vec::new();
+    for task in tasks {
+        results.push(task.await.unwrap());
+    }
+    (resolver.calls(), results)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_misses_share_one_lookup() {
+    let (calls, results) = resolve_concurrently(Outcome::Found).await;
+    assert_eq!(calls, 1);
+    for result in results {
+        assert_eq!(result.unwrap(), vec!["192.0.2.1:443".parse().unwrap()]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn lookup_errors_reach_every_waiter() {
+    let (calls, results) = resolve_concurrently(Outcome::Fail).await;
+    assert_eq!(calls, 1);
+    for result in results {
+        match result {
+            Err(ServerError::HostUnreachable(message)) => assert_eq!(message, "example.com: timed out"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+/// Resolves the same name twice, `pause` apart, and returns the lookup count.
+async fn lookups_after(cache: &DnsCache, resolver: &CountingResolver, pause: Duration) -> usize {
+    cache.resolve("example.com", 80).await.unwrap();
+    tokio::time::sleep(pause).await;
+    cache.resolve("example.com", 80).await.unwrap();
+    resolver.calls()
+}
+
+fn short_lived() -> Arc<CountingResolver> {
+    CountingResolver::new(Outcome::Found, Duration::from_secs(1))
+}
+
+#[tokio::test]
+async fn entries_expire_with_their_record_ttl() {
+    let resolver = short_lived();
+    let cache = DnsCache::with_config(DnsCacheConfig::default(), resolver.clone());
+    assert_eq!(lookups_after(&cache, &resolver, Duration::from_millis(1300)).await, 2);
+}
+
+#[tokio::test]
+async fn fixed_ttl_constructor_ignores_record_ttls() {
+    let resolver = short_lived();
+    let cache = DnsCache::with_resolver(100, Duration::from_secs(30), resolver.clone());
+    assert_eq!(lookups_after(&cache, &resolver, Duration::from_millis(1300)).await, 1);
+}
+
+fn config(min_ttl: Duration, max_ttl: Duration, negative_ttl: Duration) -> DnsCacheConfig {
+    DnsCacheConfig {
+        min_ttl,
+        max_ttl,
+        negative_ttl,
+        ..DnsCacheConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn short_ttls_are_raised_to_the_minimum() {
+    let resolver = CountingResolver::new(Outcome::Found, Duration::from_millis(100));
+    let config = config(Duration::from_secs(5), Duration::from_secs(60), Duration::from_secs(30));
+    let cache = DnsCache::with_config(config, resolver.clone());
+    assert_eq!(lookups_after(&cache, &resolver, Duration::from_millis(400)).await, 1);
+}
+
+#[tokio::test]
+async fn long_ttls_are_capped_at_the_maximum() {
+    let resolver = CountingResolver::new(Outcome::Found, Duration::from_secs(3600));
+    let config = config(Duration::ZERO, Duration::from_millis(300), Duration::from_secs(30));
+    let cache = DnsCache::with_config(config, resolver.clone());
+    assert_eq!(lookups_after(&cache, &resolver, Duration::from_millis(700)).await, 2);
+}
+
+fn assert_no_such_domain(result: Result<Vec<std::net::SocketAddr>>) {
+    match result {
+        Err(ServerError::HostUnreachable(message)) => assert_eq!(message, "example.com: no such domain"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn missing_names_are_cached_for_the_negative_ttl() {
+    // The record TTL is ignored for NXDOMAIN
+    let resolver = CountingResolver::new(Outcome::NxDomain, Duration::from_secs(3600));
+    let config = config(Duration::ZERO, Duration::from_secs(60), Duration::from_millis(500));
+    let cache = DnsCache::with_config(config, resolver.clone());
+
+    assert_no_such_domain(cache.resolve("example.com", 80).await);
+    assert_no_such_domain(cache.resolve("example.com", 80).await);
+    assert_eq!(resolver.calls(), 1);
+
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    assert_no_such_domain(cache.resolve("example.com", 80).await);
+    assert_eq!(resolver.calls(), 2);
+}
+
+#[tokio::test]
+async fn lookup_errors_are_not_cached() {
+    let resolver = CountingResolver::new(Outcome::Fail, Duration::from_secs(60));
+    let cache = DnsCache::with_config(DnsCacheConfig::default(), resolver.clone());
+    assert!(cache.resolve("example.com", 80).await.is_err());
+    assert!(cache.resolve("example.com", 80).await.is_err());
+    assert_eq!(resolver.calls(), 2);
+}
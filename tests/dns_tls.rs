@@ -0,0 +1,335 @@
+mod common;
+
+use rusk_socks5::dns_message::{self, TYPE_A};
+use rusk_socks5::resolver::Upstream;
+use rusk_socks5::resolver_doh::DohUpstream;
+use rusk_socks5::resolver_dot::{self, DotUpstream};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+
+// Written by tests/fixtures/tls/make_certs.sh; the server certificate is
+// valid for dns.test and 127.0.0.1
+const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem");
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/server.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/server.key");
+
+const ADDRESS: [u8; 4] = [192, 0, 2, 1];
+
+fn acceptor() -> TlsAcceptor {
+    let certs = CertificateDer::pem_file_iter(CERT)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(KEY).unwrap();
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// What a test server saw: connections accepted and query IDs received.
+#[derive(Default)]
+struct Seen {
+    connections: AtomicUsize,
+    ids: Mutex<Vec<u16>>,
+}
+
+impl Seen {
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    fn ids(&self) -> Vec<u16> {
+        self.ids.lock().unwrap().clone()
+    }
+}
+
+/// A DNS-over-TLS server that waits for `batch` queries before answering
+/// them in reverse order, and hangs up after each batch if `close` is set.
+async fn dot_server(batch: usize, close: bool) -> (SocketAddr, Arc<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Seen::default());
+    let acceptor = acceptor();
+    let server_seen = seen.clone();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            server_seen.connections.fetch_add(1, Ordering::SeqCst);
+            let acceptor = acceptor.clone();
+            let seen = server_seen.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(tcp).await else {
+                    return;
+                };
+                loop {
+                    let mut queries = Vec::new();
+                    for _ in 0..batch {
+                        let Ok(len) = stream.read_u16().await else {
+                            return;
+                        };
+                        let mut query = vec![0u8; len as usize];
+                        stream.read_exact(&mut query).await.unwrap();
+                        seen.ids.lock().unwrap().push(u16::from_be_bytes([query[0], query[1]]));
+                        queries.push(query);
+                    }
+                    for query in queries.iter().rev() {
+                        let response = common::answer(query, common::NOERROR, ADDRESS.into());
+                        let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                        message.extend_from_slice(&response);
+                        stream.write_all(&message).await.unwrap();
+                    }
+                    if close {
+                        let _ = stream.shutdown().await;
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+/// A DNS-over-TLS server that answers `answered` queries on its first
+/// connection and then keeps reading without answering. Later connections
+/// are answered normally.
+async fn stalling_dot_server(answered: usize) -> (SocketAddr, Arc<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Seen::default());
+    let acceptor = acceptor();
+    let server_seen = seen.clone();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let first = server_seen.connections.fetch_add(1, Ordering::SeqCst) == 0;
+            let acceptor = acceptor.clone();
+            let seen = server_seen.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(tcp).await else {
+                    return;
+                };
+                for count in 0.. {
+                    let Ok(len) = stream.read_u16().await else {
+                        return;
+                    };
+                    let mut query = vec![0u8; len as usize];
+                    stream.read_exact(&mut query).await.unwrap();
+                    seen.ids.lock().unwrap().push(u16::from_be_bytes([query[0], query[1]]));
+                    if first && count >= answered {
+                        continue;
+                    }
+                    let response = common::answer(&query, common::NOERROR, ADDRESS.into());
+                    let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                    message.extend_from_slice(&response);
+                    stream.write_all(&message).await.unwrap();
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+/// A DNS-over-HTTPS server speaking HTTP/1.1 with keep-alive on /dns-query.
+async fn doh_server() -> (SocketAddr, Arc<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Seen::default());
+    let acceptor = acceptor();
+    let server_seen = seen.clone();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            server_seen.connections.fetch_add(1, Ordering::SeqCst);
+            let acceptor = acceptor.clone();
+            let seen = server_seen.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(tcp).await {
+                    serve_http(BufReader::new(stream), &seen).await;
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+async fn serve_http<S: AsyncRead + AsyncWrite + Unpin>(mut stream: BufReader<S>, seen: &Seen) {
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        let mut content_type = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap(),
+                "content-type" => content_type = value.trim().to_string(),
+                _ => {}
+            }
+        }
+        let mut query = vec![0u8; content_length];
+        stream.read_exact(&mut query).await.unwrap();
+
+        let response = if request_line.starts_with("POST /dns-query ") && content_type == "application/dns-message" {
+            seen.ids.lock().unwrap().push(u16::from_be_bytes([query[0], query[1]]));
+            let body = common::answer(&query, common::NOERROR, ADDRESS.into());
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            response
+        } else {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+        };
+        stream.write_all(&response).await.unwrap();
+    }
+}
+
+fn dot_upstream(addr: SocketAddr, name: &str, ca_file: Option<&str>) -> DotUpstream {
+    let tls = resolver_dot::tls_config(ca_file).unwrap();
+    DotUpstream::parse(&format!("{}#{}", addr, name), tls).unwrap()
+}
+
+async fn resolve(upstream: &dyn Upstream, id: u16, name: &str) -> Result<Vec<IpAddr>, String> {
+    let query = dns_message::build_query(id, name, TYPE_A).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), upstream.exchange(&query))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map(|response| response.addrs)
+}
+
+fn expected() -> Vec<IpAddr> {
+    vec![IpAddr::from(ADDRESS)]
+}
+
+#[tokio::test]
+async fn dot_pipelines_queries_on_one_connection() {
+    let (addr, seen) = dot_server(2, false).await;
+    let upstream = dot_upstream(addr, "dns.test", Some(CA));
+
+    // The server only answers once both queries arrived, and in reverse
+    // order, so each answer has to be matched to its query by ID
+    let (first, second) = tokio::join!(resolve(&upstream, 1, "first.test"), resolve(&upstream, 2, "second.test"));
+    assert_eq!(first.unwrap(), expected());
+    assert_eq!(second.unwrap(), expected());
+
+    let (third, fourth) = tokio::join!(resolve(&upstream, 3, "third.test"), resolve(&upstream, 4, "fourth.test"));
+    assert_eq!(third.unwrap(), expected());
+    assert_eq!(fourth.unwrap(), expected());
+    assert_eq!(seen.connections(), 1);
+}
+
+#[tokio::test]
+async fn dot_renumbers_queries_with_the_same_id() {
+    let (addr, seen) = dot_server(2, false).await;
+    let upstream = dot_upstream(addr, "dns.test", Some(CA));
+
+    let (first, second) = tokio::join!(resolve(&upstream, 7, "example.test"), resolve(&upstream, 7, "example.test"));
+    assert_eq!(first.unwrap(), expected());
+    assert_eq!(second.unwrap(), expected());
+    let ids = seen.ids();
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[tokio::test]
+async fn dot_reconnects_after_the_server_closes() {
+    let (addr, seen) = dot_server(1, true).await;
+    let upstream = dot_upstream(addr, "dns.test", Some(CA));
+
+    assert_eq!(resolve(&upstream, 1, "example.test").await.unwrap(), expected());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(resolve(&upstream, 2, "example.test").await.unwrap(), expected());
+    assert_eq!(seen.connections(), 2);
+}
+
+#[tokio::test]
+async fn dot_reconnects_after_the_server_stops_answering() {
+    let (addr, seen) = stalling_dot_server(1).await;
+    let upstream = dot_upstream(addr, "dns.test", Some(CA));
+    assert_eq!(resolve(&upstream, 1, "example.test").await.unwrap(), expected());
+
+    // The connection stays open, but the query is never answered
+    let query = dns_message::build_query(2, "example.test", TYPE_A).unwrap();
+    let exchange = tokio::time::timeout(Duration::from_millis(200), upstream.exchange(&query)).await;
+    assert!(exchange.is_err());
+    assert_eq!(seen.connections(), 1);
+
+    assert_eq!(resolve(&upstream, 3, "example.test").await.unwrap(), expected());
+    assert_eq!(seen.connections(), 2);
+    assert_eq!(seen.ids().len(), 3);
+}
+
+#[tokio::test]
+async fn dot_checks_the_certificate() {
+    let (addr, _) = dot_server(1, false).await;
+
+    let wrong_name = dot_upstream(addr, "other.test", Some(CA));
+    let err = resolve(&wrong_name, 1, "example.test").await.unwrap_err();
+    assert!(err.starts_with("TLS handshake failed"), "{}", err);
+
+    let public_roots = dot_upstream(addr, "dns.test", None);
+    let err = resolve(&public_roots, 1, "example.test").await.unwrap_err();
+    assert!(err.starts_with("TLS handshake failed"), "{}", err);
+}
+
+#[tokio::test]
+async fn doh_uses_the_ca_file_and_pinned_address() {
+    let (addr, seen) = doh_server().await;
+    let url = format!("https://dns.test:{}/dns-query#127.0.0.1", addr.port());
+    let upstream = DohUpstream::parse(&url, Some(CA)).unwrap();
+
+    assert_eq!(resolve(&upstream, 0x1234, "example.test").await.unwrap(), expected());
+    assert_eq!(resolve(&upstream, 0x1235, "example.test").await.unwrap(), expected());
+    // RFC 8484 asks for ID 0, and the connection is kept open
+    assert_eq!(seen.ids(), vec![0, 0]);
+    assert_eq!(seen.connections(), 1);
+}
+
+#[tokio::test]
+async fn doh_accepts_ip_address_urls() {
+    let (addr, _) = doh_server().await;
+    let url = format!("https://127.0.0.1:{}/dns-query", addr.port());
+    let upstream = DohUpstream::parse(&url, Some(CA)).unwrap();
+    assert_eq!(resolve(&upstream, 1, "example.test").await.unwrap(), expected());
+}
+
+#[tokio::test]
+async fn doh_rejects_an_unknown_ca() {
+    let (addr, _) = doh_server().await;
+    let url = format!("https://dns.test:{}/dns-query#127.0.0.1", addr.port());
+    let upstream = DohUpstream::parse(&url, None).unwrap();
+    assert!(resolve(&upstream, 1, "example.test").await.is_err());
+}
+
+#[test]
+fn doh_requires_a_pinned_address_for_host_names() {
+    let err = DohUpstream::parse("https://dns.test/dns-query", None).err().unwrap();
+    assert!(err.contains("append the server's IP address"), "{}", err);
+    assert!(DohUpstream::parse("https://dns.test/dns-query#not-an-ip", None).is_err());
+    assert!(DohUpstream::parse("http://127.0.0.1/dns-query", None).is_err());
+    assert!(DohUpstream::parse("https://[::1]/dns-query", None).is_ok());
+    assert!(DohUpstream::parse("https://dns.test/dns-query#192.0.2.53", None).is_ok());
+}
@@ -0,0 +1,71 @@
+#!/usr/bin/env python3
+"""Writes the small IPv4 MaxMind DB files used by tests/geoip.rs.
+
+Run from this directory: python3 make_geoip.py
+"""
+import ipaddress
+
+def enc_uint(t, v, ext=None):
+    b = v.to_bytes((v.bit_length()+7)//8, 'big') if v else b''
+    return ctrl(t, len(b)) + b
+def ctrl(t, size):
+    assert size < 29
+    if t <= 7: return bytes([(t << 5) | size])
+    return bytes([size, t - 7])
+def enc(v):
+    if isinstance(v, str):
+        b = v.encode(); return ctrl(2, len(b)) + b
+    if isinstance(v, dict):
+        out = ctrl(7, len(v))
+        for k, x in v.items(): out += enc(k) + enc(x)
+        return out
+    if isinstance(v, list):
+        out = ctrl(11, len(v))
+        for x in v: out += enc(x)
+        return out
+    if isinstance(v, tuple):  # (type, value)
+        return enc_uint(*v)
+    raise TypeError(v)
+def build(entries, dbtype, out):
+    # entries: list of (cidr, record); later entries override earlier
+    nodes = [[None, None]]
+    for cidr, rec in entries:
+        net = ipaddress.ip_network(cidr)
+        bits = int(net.network_address); plen = net.prefixlen
+        n = 0
+        for d in range(plen):
+            bit = (bits >> (31 - d)) & 1
+            if d == plen - 1:
+                nodes[n][bit] = ('data', rec); break
+            cur = nodes[n][bit]
+            if cur is None or cur[0] == 'data':
+                nodes.append([cur, cur]); nodes[n][bit] = ('node', len(nodes) - 1)
+            n = nodes[n][bit][1]
+    data = b''; offsets = {}
+    for _, rec in entries:
+        k = repr(rec)
+        if k not in offsets:
+            offsets[k] = len(data); data += enc(rec)
+    nc = len(nodes)
+    def val(r):
+        if r is None: return nc
+        if r[0] == 'node': return r[1]
+        return nc + 16 + offsets[repr(r[1])]
+    tree = b''
+    for l, r in nodes:
+        tree += val(l).to_bytes(3, 'big') + val(r).to_bytes(3, 'big')
+    meta = {"node_count": (6, nc), "record_size": (5, 24), "ip_version": (5, 4), "database_type": dbtype,
+            "languages": ["en"], "binary_format_major_version": (5, 2), "binary_format_minor_version": (5, 0),
+            "build_epoch": (9, 1700000000), "description": {"en": "test"}}
+    open(out, 'wb').write(tree + b'\0' * 16 + data + b'\xab\xcd\xefMaxMind.com' + enc(meta))
+
+
+NETWORKS = [
+    # network, country, ASN
+    ("1.1.1.0/24", "AU", 13335),
+    ("8.8.8.0/24", "US", 15169),
+    ("81.2.69.0/24", "GB", 20712),
+]
+
+build([(net, {"country": {"iso_code": cc}}) for net, cc, _ in NETWORKS], "Test-Country", "geo-country.mmdb")
+build([(net, {"autonomous_system_number": (6, asn)}) for net, _, asn in NETWORKS], "Test-ASN", "geo-asn.mmdb")
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Writes the CA and server certificate used by tests/dns_tls.rs. The server
+# certificate is valid for dns.test and 127.0.0.1.
+set -e
+cd "$(dirname "$0")"
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 \
+    -subj "/CN=Test DNS CA" -keyout ca.key -out ca.pem \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign"
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -subj "/CN=dns.test" -keyout server.key -out server.csr
+printf 'subjectAltName=DNS:dns.test,IP:127.0.0.1\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\n' > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile server.ext -out server.pem
+rm -f ca.key ca.srl server.csr server.ext
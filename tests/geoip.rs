@@ -0,0 +1,84 @@
+use rusk_socks5::dest_filter::{Action, DestFilter};
+use rusk_socks5::geoip::{GeoFilter, GeoIpDb, GeoRule};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+// The fixtures are written by tests/fixtures/make_geoip.py and map
+// 1.1.1.0/24 to AU / AS13335, 8.8.8.0/24 to US / AS15169 and
+// 81.2.69.0/24 to GB / AS20712.
+const COUNTRY_DB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geo-country.mmdb");
+const ASN_DB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geo-asn.mmdb");
+
+fn db() -> Arc<GeoIpDb> {
+    Arc::new(GeoIpDb::open(Some(COUNTRY_DB), Some(ASN_DB)).unwrap())
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn rules(list: &[&str]) -> Vec<String> {
+    list.iter().map(|r| r.to_string()).collect()
+}
+
+#[test]
+fn looks_up_country_and_asn() {
+    let db = db();
+    assert_eq!(db.country(&ip("1.1.1.1")).as_deref(), Some("AU"));
+    assert_eq!(db.country(&ip("::ffff:8.8.8.8")).as_deref(), Some("US"));
+    assert_eq!(db.asn(&ip("81.2.69.142")), Some(20712));
+    assert_eq!(db.country(&ip("9.9.9.9")), None);
+    assert_eq!(db.asn(&ip("9.9.9.9")), None);
+}
+
+#[test]
+fn parses_rules_case_insensitively() {
+    for rule in ["asn:13335", "ASN:13335", "asn:AS13335", "asn:as13335", "Asn:As13335"] {
+        assert_eq!(GeoRule::parse(rule), Ok(GeoRule::Asn(13335)), "{}", rule);
+    }
+    assert_eq!(GeoRule::parse("Country:au"), Ok(GeoRule::Country("AU".to_string())));
+    assert!(GeoRule::parse("asn:ASX").is_err());
+    assert!(GeoRule::parse("country:AUS").is_err());
+}
+
+#[test]
+fn source_rules_match_country_and_asn() {
+    let allow = GeoFilter::new(db(), &rules(&["country:au", "asn:AS15169"]), &[]).unwrap();
+    assert!(allow.allows(&ip("1.1.1.1")));
+    assert!(allow.allows(&ip("8.8.8.8")));
+    assert!(!allow.allows(&ip("81.2.69.142")));
+    // Unknown addresses match no rule, so an allow list refuses them
+    assert!(!allow.allows(&ip("9.9.9.9")));
+
+    let deny = GeoFilter::new(db(), &[], &rules(&["country:GB", "asn:13335"])).unwrap();
+    assert!(!deny.allows(&ip("81.2.69.142")));
+    assert!(!deny.allows(&ip("1.1.1.1")));
+    assert!(deny.allows(&ip("8.8.8.8")));
+    assert!(deny.allows(&ip("9.9.9.9")));
+}
+
+#[test]
+fn deny_wins_over_allow() {
+    let filter = GeoFilter::new(db(), &rules(&["country:AU"]), &rules(&["asn:13335"])).unwrap();
+    assert!(!filter.allows(&ip("1.1.1.1")));
+}
+
+#[test]
+fn destination_rules_apply_to_resolved_addresses() {
+    let mut filter = DestFilter::new(Action::Allow);
+    filter.set_geo_filter(GeoFilter::new(db(), &[], &rules(&["country:US"])).unwrap());
+    assert!(!filter.allows(Some("dns.google"), ip("8.8.8.8"), 443));
+    assert!(filter.allows(Some("one.one.one.one"), ip("1.1.1.1"), 443));
+
+    let mut filter = DestFilter::new(Action::Allow);
+    filter.set_geo_filter(GeoFilter::new(db(), &rules(&["asn:13335"]), &[]).unwrap());
+    assert!(filter.allows(None, ip("1.1.1.1"), 80));
+    assert!(!filter.allows(None, ip("81.2.69.142"), 80));
+}
+
+#[test]
+fn rules_need_the_matching_database() {
+    let country_only = Arc::new(GeoIpDb::open(Some(COUNTRY_DB), None).unwrap());
+    assert!(GeoFilter::new(country_only.clone(), &rules(&["country:AU"]), &[]).is_ok());
+    assert!(GeoFilter::new(country_only, &[], &rules(&["asn:13335"])).is_err());
+}
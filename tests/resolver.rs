@@ -0,0 +1,208 @@
+mod common;
+
+use rusk_socks5::errors::ServerError;
+use rusk_socks5::resolver::{PlainUpstream, Resolver, StubConfig, StubResolver, Upstream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Behavior {
+    Answer,
+    /// Sets TC over UDP; the full answer is only available over TCP.
+    Truncate,
+    ServFail,
+    NxDomain,
+    Silent,
+    /// Sends a reply with the wrong ID before the real one.
+    SpoofFirst,
+}
+
+/// A nameserver on 127.0.0.1 answering A queries with `address` and AAAA
+/// queries with no records.
+struct StubServer {
+    addr: SocketAddr,
+    address: Ipv4Addr,
+    udp_queries: Arc<AtomicUsize>,
+    tcp_queries: Arc<AtomicUsize>,
+}
+
+impl StubServer {
+    async fn start(behavior: Behavior, address: Ipv4Addr) -> Self {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let udp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+
+        let counter = udp_queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, peer) = udp.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = &buf[..n];
+                match behavior {
+                    Behavior::Silent => continue,
+                    Behavior::SpoofFirst => {
+                        let mut spoofed = query.to_vec();
+                        spoofed[0] ^= 0xff;
+                        let _ = udp.send_to(&answer(&spoofed, Behavior::Answer, [203, 0, 113, 66].into()), peer).await;
+                    }
+                    _ => {}
+                }
+                let _ = udp.send_to(&answer(query, behavior, address), peer).await;
+            }
+        });
+
+        let counter = tcp_queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut query = vec![0u8; len as usize];
+                        stream.read_exact(&mut query).await.unwrap();
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        // TCP always gets the whole answer
+                        let behavior = if behavior == Behavior::Truncate { Behavior::Answer } else { behavior };
+                        let response = answer(&query, behavior, address);
+                        let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                        message.extend_from_slice(&response);
+                        stream.write_all(&message).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        StubServer {
+            addr,
+            address,
+            udp_queries,
+            tcp_queries,
+        }
+    }
+
+    fn upstream(&self) -> Arc<dyn Upstream> {
+        Arc::new(PlainUpstream::new(self.addr, false))
+    }
+
+    fn queries(&self) -> usize {
+        self.udp_queries.load(Ordering::SeqCst) + self.tcp_queries.load(Ordering::SeqCst)
+    }
+}
+
+fn answer(query: &[u8], behavior: Behavior, address: Ipv4Addr) -> Vec<u8> {
+    let flags = match behavior {
+        Behavior::ServFail => common::NOERROR | 2,
+        Behavior::NxDomain => common::NOERROR | 3,
+        // TC
+        Behavior::Truncate => common::NOERROR | 0x0200,
+        _ => common::NOERROR,
+    };
+    common::answer(query, flags, address)
+}
+
+fn resolver(servers: &[&StubServer], attempts: u32) -> StubResolver {
+    StubResolver::new(StubConfig {
+        upstreams: servers.iter().map(|s| s.upstream()).collect(),
+        timeout: Duration::from_millis(200),
+        attempts,
+    })
+    .unwrap()
+}
+
+fn v4(address: Ipv4Addr) -> Vec<IpAddr> {
+    vec![IpAddr::V4(address)]
+}
+
+#[tokio::test]
+async fn answers_with_addresses_and_ttl() {
+    let server = StubServer::start(Behavior::Answer, [192, 0, 2, 1].into()).await;
+    let lookup = resolver(&[&server], 1).lookup("example.test").await.unwrap();
+    assert_eq!(lookup.addrs, v4(server.address));
+    assert_eq!(lookup.ttl, Some(Duration::from_secs(60)));
+}
+
+#[tokio::test]
+async fn falls_back_to_tcp_when_truncated() {
+    let server = StubServer::start(Behavior::Truncate, [192, 0, 2, 1].into()).await;
+    let lookup = resolver(&[&server], 1).lookup("big.test").await.unwrap();
+    assert_eq!(lookup.addrs, v4(server.address));
+    assert_eq!(server.udp_queries.load(Ordering::SeqCst), 2);
+    assert_eq!(server.tcp_queries.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn tcp_only_skips_udp() {
+    let server = StubServer::start(Behavior::Answer, [192, 0, 2, 1].into()).await;
+    let resolver = StubResolver::new(StubConfig {
+        upstreams: vec![Arc::new(PlainUpstream::new(server.addr, true))],
+        timeout: Duration::from_millis(200),
+        attempts: 1,
+    })
+    .unwrap();
+    assert_eq!(resolver.lookup("example.test").await.unwrap().addrs, v4(server.address));
+    assert_eq!(server.udp_queries.load(Ordering::SeqCst), 0);
+    assert_eq!(server.tcp_queries.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn retries_on_the_next_server() {
+    let silent = StubServer::start(Behavior::Silent, [192, 0, 2, 1].into()).await;
+    let failing = StubServer::start(Behavior::ServFail, [192, 0, 2, 2].into()).await;
+    let good = StubServer::start(Behavior::Answer, [192, 0, 2, 3].into()).await;
+
+    let start = Instant::now();
+    let lookup = resolver(&[&silent, &failing, &good], 3).lookup("example.test").await.unwrap();
+    assert_eq!(lookup.addrs, v4(good.address));
+    // Only the silent server costs a timeout
+    assert!(start.elapsed() < Duration::from_millis(400), "{:?}", start.elapsed());
+    assert_eq!(silent.queries(), 1);
+    assert_eq!(failing.queries(), 2);
+    assert_eq!(good.queries(), 2);
+}
+
+#[tokio::test]
+async fn fails_after_the_last_attempt() {
+    let silent = StubServer::start(Behavior::Silent, [192, 0, 2, 1].into()).await;
+    let failing = StubServer::start(Behavior::ServFail, [192, 0, 2, 2].into()).await;
+
+    match resolver(&[&silent, &failing], 2).lookup("example.test").await {
+        Err(ServerError::HostUnreachable(message)) => assert!(message.starts_with("example.test: "), "{}", message),
+        other => panic!("unexpected result: {:?}", other.map(|l| l.addrs)),
+    }
+    assert_eq!(silent.queries() + failing.queries(), 4);
+}
+
+#[tokio::test]
+async fn spreads_queries_round_robin() {
+    let first = StubServer::start(Behavior::Answer, [192, 0, 2, 1].into()).await;
+    let second = StubServer::start(Behavior::Answer, [192, 0, 2, 2].into()).await;
+    let resolver = resolver(&[&first, &second], 1);
+    for _ in 0..4 {
+        resolver.lookup("example.test").await.unwrap();
+    }
+    assert_eq!(first.queries(), 4);
+    assert_eq!(second.queries(), 4);
+}
+
+#[tokio::test]
+async fn nxdomain_is_an_empty_answer() {
+    let server = StubServer::start(Behavior::NxDomain, [192, 0, 2, 1].into()).await;
+    let lookup = resolver(&[&server], 3).lookup("missing.test").await.unwrap();
+    assert!(lookup.addrs.is_empty());
+    // NXDOMAIN is an answer, not a failure to retry
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn ignores_replies_with_another_id() {
+    let server = StubServer::start(Behavior::SpoofFirst, [192, 0, 2, 1].into()).await;
+    let lookup = resolver(&[&server], 1).lookup("example.test").await.unwrap();
+    assert_eq!(lookup.addrs, v4(server.address));
+}
@@ -0,0 +1,456 @@
+use async_trait::async_trait;
+use rusk_socks5::auth::{AnonymousAuthenticator, Authenticator, StaticAuthenticator};
+use rusk_socks5::connector::Connector;
+use rusk_socks5::dest_filter::{Action, DestFilter};
+use rusk_socks5::dns_cache::{DnsCache, DnsCacheConfig};
+use rusk_socks5::errors::{Result, ServerError};
+use rusk_socks5::handlers::{ConnectionHandler, encode_socket_addr};
+use rusk_socks5::resolver::{Lookup, Resolver};
+use rusk_socks5::server::ServerConfig;
+use rusk_socks5::users::UserStore;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Resolves every name to 127.0.0.1, except `.invalid` names, whose
+/// lookups fail.
+struct Loopback;
+
+#[async_trait]
+impl Resolver for Loopback {
+    async fn lookup(&self, host: &str) -> Result<Lookup> {
+        if host.ends_with(".invalid") {
+            return Err(ServerError::HostUnreachable(format!("{}: no such host", host)));
+        }
+        Ok(Lookup {
+            addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            ttl: Some(Duration::from_secs(60)),
+        })
+    }
+}
+
+fn config(allow_anonymous: bool, disable_socks4: bool) -> ServerConfig {
+    ServerConfig {
+        address: "127.0.0.1".to_string(),
+        port: 0,
+        allow_anonymous,
+        disable_socks4,
+        username: None,
+        password: None,
+        users_file: None,
+        auth_webhook: None,
+        auth_ldap: None,
+        auth_radius: None,
+        auth_lockout: None,
+        dns_cache_capacity: 1000,
+        dns_cache_ttl_secs: 60,
+        dns_min_ttl_secs: 0,
+        dns_max_ttl_secs: 86_400,
+        dns_negative_ttl_secs: 0,
+        dns_servers: Vec::new(),
+        dns_timeout_ms: 2000,
+        dns_attempts: 2,
+        dns_tcp: false,
+        dns_over_tls: Vec::new(),
+        dns_over_https: Vec::new(),
+        dns_tls_ca_file: None,
+        max_connections: 100,
+        bind_timeout_secs: 5,
+        connect_timeout_ms: 2000,
+        connect_attempt_delay_ms: 250,
+        ip_whitelist: Vec::new(),
+        ip_blacklist: Vec::new(),
+        ip_whitelist_files: Vec::new(),
+        ip_blacklist_files: Vec::new(),
+        ip_rules_poll_secs: 0,
+        dest_rules: Vec::new(),
+        dest_default_deny: false,
+        disable_ssrf_protection: true,
+        private_dest_allow: Vec::new(),
+        geoip_country_db: None,
+        geoip_asn_db: None,
+        geo_source_allow: Vec::new(),
+        geo_source_deny: Vec::new(),
+        geo_dest_allow: Vec::new(),
+        geo_dest_deny: Vec::new(),
+    }
+}
+
+/// A server on 127.0.0.1 serving every client with a [`ConnectionHandler`].
+/// Names resolve through [`Loopback`] and loopback destinations are allowed
+/// unless `config.dest_rules` deny them.
+async fn server(config: ServerConfig, authenticator: Arc<dyn Authenticator>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut dest_filter = DestFilter::from_strings(&config.dest_rules, Action::Allow).unwrap();
+    dest_filter.set_block_private(!config.disable_ssrf_protection);
+    let dest_filter = Arc::new(dest_filter);
+    let config = Arc::new(config);
+    let dns_cache = Arc::new(DnsCache::with_config(DnsCacheConfig::default(), Arc::new(Loopback)));
+    let connector = Arc::new(Connector::default());
+    tokio::spawn(async move {
+        loop {
+            let (socket, client) = listener.accept().await.unwrap();
+            let mut handler = ConnectionHandler::new(
+                socket,
+                client,
+                config.clone(),
+                dns_cache.clone(),
+                dest_filter.clone(),
+                connector.clone(),
+                authenticator.clone(),
+            );
+            tokio::spawn(async move {
+                if handler.handle().await.is_err() {
+                    let _ = handler.close().await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn open_server() -> SocketAddr {
+    server(config(true, false), Arc::new(AnonymousAuthenticator::new())).await
+}
+
+/// A server requiring a password, which SOCKS4 cannot send.
+async fn password_server() -> SocketAddr {
+    let users = UserStore::parse(&format!("alice:{}", bcrypt::hash("alice-pw", 4).unwrap())).unwrap();
+    server(config(false, false), Arc::new(StaticAuthenticator::new(users))).await
+}
+
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn read_exactly<const N: usize>(stream: &mut TcpStream) -> [u8; N] {
+    let mut buf = [0u8; N];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("no reply from the server")
+        .unwrap();
+    buf
+}
+
+async fn assert_echoes(stream: &mut TcpStream) {
+    stream.write_all(b"hello").await.unwrap();
+    assert_eq!(&read_exactly::<5>(stream).await, b"hello");
+}
+
+async fn assert_closed(stream: &mut TcpStream) {
+    let mut buf = [0u8; 1];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("connection still open");
+    assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+}
+
+/// A SOCKS4 request; `domain` turns it into SOCKS4a with DSTIP 0.0.0.1.
+fn socks4_request(command: u8, target: SocketAddr, user_id: &str, domain: Option<&str>) -> Vec<u8> {
+    let mut request = vec![4, command];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    match (domain, target.ip()) {
+        (Some(_), _) => request.extend_from_slice(&[0, 0, 0, 1]),
+        (None, IpAddr::V4(ip)) => request.extend_from_slice(&ip.octets()),
+        (None, IpAddr::V6(ip)) => panic!("SOCKS4 cannot carry {}", ip),
+    }
+    request.extend_from_slice(user_id.as_bytes());
+    request.push(0);
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    request
+}
+
+/// Splits a SOCKS4 reply into its code and DSTPORT/DSTIP.
+fn socks4_reply(reply: [u8; 8]) -> (u8, SocketAddr) {
+    assert_eq!(reply[0], 0, "{:?}", reply);
+    let port = u16::from_be_bytes([reply[2], reply[3]]);
+    let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+    (reply[1], SocketAddr::from((ip, port)))
+}
+
+#[tokio::test]
+async fn socks4_connect_relays_bytes() {
+    let echo = echo_server().await;
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    client.write_all(&socks4_request(1, echo, "bob", None)).await.unwrap();
+
+    let (code, bound) = socks4_reply(read_exactly(&mut client).await);
+    assert_eq!(code, 90);
+    assert_eq!(bound.ip(), Ipv4Addr::LOCALHOST);
+    assert_ne!(bound.port(), 0);
+    assert_echoes(&mut client).await;
+}
+
+#[tokio::test]
+async fn socks4a_resolves_domains() {
+    let echo = echo_server().await;
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    client
+        .write_all(&socks4_request(1, echo, "bob", Some("echo.test")))
+        .await
+        .unwrap();
+
+    let (code, _) = socks4_reply(read_exactly(&mut client).await);
+    assert_eq!(code, 90);
+    assert_echoes(&mut client).await;
+}
+
+#[tokio::test]
+async fn socks4_is_rejected_without_anonymous_access() {
+    let echo = echo_server().await;
+    for domain in [None, Some("echo.test")] {
+        let mut client = TcpStream::connect(password_server().await).await.unwrap();
+        // The USERID is not a credential
+        client.write_all(&socks4_request(1, echo, "alice", domain)).await.unwrap();
+
+        assert_eq!(read_exactly::<8>(&mut client).await, [0, 91, 0, 0, 0, 0, 0, 0]);
+        assert_closed(&mut client).await;
+    }
+}
+
+#[tokio::test]
+async fn socks4_is_rejected_when_disabled() {
+    let echo = echo_server().await;
+    let addr = server(config(true, true), Arc::new(AnonymousAuthenticator::new())).await;
+    for domain in [None, Some("echo.test")] {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&socks4_request(1, echo, "bob", domain)).await.unwrap();
+
+        assert_eq!(read_exactly::<8>(&mut client).await, [0, 91, 0, 0, 0, 0, 0, 0]);
+        assert_closed(&mut client).await;
+    }
+}
+
+#[tokio::test]
+async fn socks4_bind_sends_both_replies() {
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    let expected_peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    client.write_all(&socks4_request(2, expected_peer, "bob", None)).await.unwrap();
+
+    // First reply: where the peer should connect to
+    let (code, listen_address) = socks4_reply(read_exactly(&mut client).await);
+    assert_eq!(code, 90);
+    assert_eq!(listen_address.ip(), Ipv4Addr::LOCALHOST);
+    let mut peer = TcpStream::connect(listen_address).await.unwrap();
+
+    // Second reply: who connected
+    let (code, peer_address) = socks4_reply(read_exactly(&mut client).await);
+    assert_eq!(code, 90);
+    assert_eq!(peer_address, peer.local_addr().unwrap());
+
+    peer.write_all(b"from peer").await.unwrap();
+    assert_eq!(&read_exactly::<9>(&mut client).await, b"from peer");
+    client.write_all(b"to peer").await.unwrap();
+    assert_eq!(&read_exactly::<7>(&mut peer).await, b"to peer");
+}
+
+/// Negotiates "no authentication" and sends a request for `target`.
+async fn socks5_request(stream: &mut TcpStream, command: u8, target: &[u8]) {
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly::<2>(stream).await, [5, 0]);
+    let mut request = vec![5, command, 0];
+    request.extend_from_slice(target);
+    stream.write_all(&request).await.unwrap();
+}
+
+fn ip_target(addr: SocketAddr) -> Vec<u8> {
+    let mut target = Vec::new();
+    encode_socket_addr(&mut target, &addr);
+    target
+}
+
+fn domain_target(domain: &str, port: u16) -> Vec<u8> {
+    let mut target = vec![3, domain.len() as u8];
+    target.extend_from_slice(domain.as_bytes());
+    target.extend_from_slice(&port.to_be_bytes());
+    target
+}
+
+/// Reads a SOCKS5 reply and returns its REP code and BND.ADDR/BND.PORT.
+async fn socks5_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+    let [version, rep, reserved, atyp] = read_exactly::<4>(stream).await;
+    assert_eq!((version, reserved), (5, 0));
+    let ip = match atyp {
+        1 => IpAddr::from(read_exactly::<4>(stream).await),
+        4 => IpAddr::from(read_exactly::<16>(stream).await),
+        _ => panic!("unexpected ATYP {}", atyp),
+    };
+    let port = u16::from_be_bytes(read_exactly::<2>(stream).await);
+    (rep, SocketAddr::new(ip, port))
+}
+
+#[tokio::test]
+async fn socks5_connect_reports_the_outbound_address() {
+    let echo = echo_server().await;
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    socks5_request(&mut client, 1, &ip_target(echo)).await;
+
+    let (rep, bound) = socks5_reply(&mut client).await;
+    assert_eq!(rep, 0);
+    assert_eq!(bound.ip(), Ipv4Addr::LOCALHOST);
+    assert_ne!(bound.port(), 0);
+    assert_echoes(&mut client).await;
+}
+
+#[tokio::test]
+async fn socks5_connect_reports_ipv6_outbound_addresses() {
+    // Skipped where the host has no IPv6 loopback
+    let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+        return;
+    };
+    let target = listener.local_addr().unwrap();
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    socks5_request(&mut client, 1, &ip_target(target)).await;
+    let (mut inbound, from) = listener.accept().await.unwrap();
+
+    let (rep, bound) = socks5_reply(&mut client).await;
+    assert_eq!(rep, 0);
+    assert_eq!(bound.ip(), Ipv6Addr::LOCALHOST);
+    assert_eq!(bound, from);
+    inbound.write_all(b"hello").await.unwrap();
+    assert_eq!(&read_exactly::<5>(&mut client).await, b"hello");
+}
+
+/// Sends a CONNECT request for `target` and returns the REP code, checking
+/// that failures carry 0.0.0.0:0 and close the connection.
+async fn connect_rep(addr: SocketAddr, target: &[u8]) -> u8 {
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    socks5_request(&mut client, 1, target).await;
+    let (rep, bound) = socks5_reply(&mut client).await;
+    assert_eq!(bound, "0.0.0.0:0".parse().unwrap());
+    assert_closed(&mut client).await;
+    rep
+}
+
+#[tokio::test]
+async fn socks5_connect_failures_carry_their_rep_code() {
+    let mut config = config(true, false);
+    config.dest_rules = vec!["deny blocked.test".to_string(), "deny 127.0.0.2".to_string()];
+    let addr = server(config, Arc::new(AnonymousAuthenticator::new())).await;
+    let closed_port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    assert_eq!(connect_rep(addr, &domain_target("blocked.test", 80)).await, 0x02);
+    assert_eq!(connect_rep(addr, &ip_target("127.0.0.2:80".parse().unwrap())).await, 0x02);
+    assert_eq!(connect_rep(addr, &domain_target("host.invalid", 80)).await, 0x04);
+    assert_eq!(connect_rep(addr, &ip_target(closed_port)).await, 0x05);
+}
+
+#[tokio::test]
+async fn socks5_refuses_ssrf_targets() {
+    let mut config = config(true, false);
+    config.disable_ssrf_protection = false;
+    let addr = server(config, Arc::new(AnonymousAuthenticator::new())).await;
+    let echo = echo_server().await;
+
+    assert_eq!(connect_rep(addr, &ip_target(echo)).await, 0x02);
+    assert_eq!(connect_rep(addr, &domain_target("echo.test", echo.port())).await, 0x02);
+}
+
+#[tokio::test]
+async fn socks5_rejects_unknown_commands_and_address_types() {
+    let addr = open_server().await;
+
+    // Rejected as soon as the field is read, so nothing more is sent
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly::<2>(&mut client).await, [5, 0]);
+    client.write_all(&[5, 9]).await.unwrap();
+    assert_eq!(socks5_reply(&mut client).await, (0x07, "0.0.0.0:0".parse().unwrap()));
+    assert_closed(&mut client).await;
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_exactly::<2>(&mut client).await, [5, 0]);
+    client.write_all(&[5, 1, 0, 5]).await.unwrap();
+    assert_eq!(socks5_reply(&mut client).await, (0x08, "0.0.0.0:0".parse().unwrap()));
+    assert_closed(&mut client).await;
+}
+
+#[tokio::test]
+async fn socks5_bind_sends_both_replies() {
+    for target in [ip_target("127.0.0.1:0".parse().unwrap()), domain_target("peer.test", 0)] {
+        let mut client = TcpStream::connect(open_server().await).await.unwrap();
+        socks5_request(&mut client, 2, &target).await;
+
+        // First reply: where the peer should connect to
+        let (rep, listen_address) = socks5_reply(&mut client).await;
+        assert_eq!(rep, 0);
+        assert_eq!(listen_address.ip(), Ipv4Addr::LOCALHOST);
+        let mut peer = TcpStream::connect(listen_address).await.unwrap();
+
+        // Second reply: who connected
+        let (rep, peer_address) = socks5_reply(&mut client).await;
+        assert_eq!(rep, 0);
+        assert_eq!(peer_address, peer.local_addr().unwrap());
+
+        peer.write_all(b"from peer").await.unwrap();
+        assert_eq!(&read_exactly::<9>(&mut client).await, b"from peer");
+        client.write_all(b"to peer").await.unwrap();
+        assert_eq!(&read_exactly::<7>(&mut peer).await, b"to peer");
+    }
+}
+
+#[tokio::test]
+async fn socks5_bind_accepts_any_peer_for_an_unspecified_address() {
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    socks5_request(&mut client, 2, &ip_target("0.0.0.0:0".parse().unwrap())).await;
+
+    let (_, listen_address) = socks5_reply(&mut client).await;
+    let peer = TcpStream::connect(listen_address).await.unwrap();
+    let (rep, peer_address) = socks5_reply(&mut client).await;
+    assert_eq!(rep, 0);
+    assert_eq!(peer_address, peer.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn socks5_bind_refuses_unexpected_peers() {
+    let mut client = TcpStream::connect(open_server().await).await.unwrap();
+    socks5_request(&mut client, 2, &ip_target("192.0.2.1:21".parse().unwrap())).await;
+
+    let (rep, listen_address) = socks5_reply(&mut client).await;
+    assert_eq!(rep, 0);
+    let mut peer = TcpStream::connect(listen_address).await.unwrap();
+
+    // Connection not allowed by ruleset
+    assert_eq!(socks5_reply(&mut client).await, (2, "0.0.0.0:0".parse().unwrap()));
+    assert_closed(&mut client).await;
+    assert_closed(&mut peer).await;
+}
+
+#[tokio::test]
+async fn socks5_bind_times_out_without_a_peer() {
+    let mut config = config(true, false);
+    config.bind_timeout_secs = 1;
+    let addr = server(config, Arc::new(AnonymousAuthenticator::new())).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    socks5_request(&mut client, 2, &ip_target("127.0.0.1:0".parse().unwrap())).await;
+
+    let (rep, listen_address) = socks5_reply(&mut client).await;
+    assert_eq!(rep, 0);
+
+    // TTL expired, then the listener is gone
+    assert_eq!(socks5_reply(&mut client).await, (6, "0.0.0.0:0".parse().unwrap()));
+    assert_closed(&mut client).await;
+    assert!(TcpStream::connect(listen_address).await.is_err());
+}
@@ -0,0 +1,123 @@
+use rusk_socks5::dest_filter::DestFilter;
+use rusk_socks5::dns_cache::DnsCache;
+use rusk_socks5::handlers::TargetAddr;
+use rusk_socks5::udp_relay::{UdpRelay, encode_udp_datagram, parse_udp_header};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// A UDP server echoing every datagram and recording its payload.
+async fn echo_server() -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let seen = received.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            seen.lock().unwrap().push(buf[..n].to_vec());
+            socket.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    (addr, received)
+}
+
+/// Starts a relay for `client_port` on 127.0.0.1 and returns its address,
+/// the client end of its control connection and the task running it.
+async fn relay(client_port: u16) -> (SocketAddr, TcpStream, JoinHandle<rusk_socks5::errors::Result<()>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_address = socket.local_addr().unwrap();
+    let mut dest_filter = DestFilter::default();
+    dest_filter.set_block_private(false);
+    let relay = UdpRelay::new(
+        socket,
+        Ipv4Addr::LOCALHOST.into(),
+        client_port,
+        Arc::new(DnsCache::new_default()),
+        Arc::new(dest_filter),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (mut control, _) = listener.accept().await.unwrap();
+    let task = tokio::spawn(async move { relay.run(&mut control).await });
+    (relay_address, client, task)
+}
+
+/// Sends `payload` to `target` through the relay and returns the echoed
+/// datagram's source and payload.
+async fn exchange(client: &UdpSocket, relay: SocketAddr, target: SocketAddr, payload: &[u8]) -> (SocketAddr, Vec<u8>) {
+    client.send_to(&encode_udp_datagram(&target, payload), relay).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let (n, from) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .expect("no reply through the relay")
+        .unwrap();
+    assert_eq!(from, relay);
+    let (frag, source, offset) = parse_udp_header(&buf[..n]).unwrap();
+    assert_eq!(frag, 0);
+    let TargetAddr::Ip(source) = source else {
+        panic!("reply carries a domain: {}", source);
+    };
+    (source, buf[offset..n].to_vec())
+}
+
+#[tokio::test]
+async fn relays_datagrams_both_ways() {
+    let (echo, _) = echo_server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (relay, _control, _task) = relay(client.local_addr().unwrap().port()).await;
+
+    let (source, payload) = exchange(&client, relay, echo, b"ping").await;
+    assert_eq!(source, echo);
+    assert_eq!(payload, b"ping");
+}
+
+#[tokio::test]
+async fn drops_datagrams_from_other_source_ports() {
+    let (echo, received) = echo_server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (relay, _control, _task) = relay(client.local_addr().unwrap().port()).await;
+
+    // The port announced in the request is pinned before the first datagram
+    stranger.send_to(&encode_udp_datagram(&echo, b"stray"), relay).await.unwrap();
+    exchange(&client, relay, echo, b"first").await;
+    stranger.send_to(&encode_udp_datagram(&echo, b"stray"), relay).await.unwrap();
+    exchange(&client, relay, echo, b"second").await;
+
+    assert_eq!(*received.lock().unwrap(), [b"first".to_vec(), b"second".to_vec()]);
+}
+
+#[tokio::test]
+async fn pins_the_port_of_the_first_datagram() {
+    let (echo, received) = echo_server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (relay, _control, _task) = relay(0).await;
+
+    exchange(&client, relay, echo, b"first").await;
+    stranger.send_to(&encode_udp_datagram(&echo, b"stray"), relay).await.unwrap();
+    exchange(&client, relay, echo, b"second").await;
+
+    assert_eq!(*received.lock().unwrap(), [b"first".to_vec(), b"second".to_vec()]);
+}
+
+#[tokio::test]
+async fn ends_when_the_control_connection_closes() {
+    let (echo, _) = echo_server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (relay, control, task) = relay(client.local_addr().unwrap().port()).await;
+    exchange(&client, relay, echo, b"ping").await;
+
+    drop(control);
+    timeout(Duration::from_secs(5), task)
+        .await
+        .expect("relay still running after the control connection closed")
+        .unwrap()
+        .unwrap();
+}